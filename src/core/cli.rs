@@ -1,5 +1,9 @@
 use clap::{App, Arg, ArgMatches};
 
+use crate::core::color::ColorScheme;
+use crate::core::flag::{hyperlink_template, Flags};
+use crate::core::query::Query;
+
 ///Struct encapsulating the CLI and its arguments.
 pub(crate) struct Cli<'cli> {
     app: App<'cli>,
@@ -14,14 +18,35 @@ impl<'cli> Cli<'cli> {
         .about("Searches for patterns. Prints lines that match those patterns to the standard output.")
         .arg(
             Arg::with_name("pattern")
-                .help("The pattern to search for")
+                .help("The pattern to search for. When --regexp, --query or --near is given, this is the first file to search in instead")
+                .takes_value(true)
+                .required_unless_present_any(["regexp", "query", "near"]),
+        )
+        .arg(
+            Arg::with_name("regexp")
+                .help("A pattern to search for. Can be given more than once, to search for lines matching any of the patterns. With --color, each pattern is highlighted in a different color")
+                .short('e')
+                .long("regexp")
+                .value_name("PATTERN")
                 .takes_value(true)
-                .required(true),
+                .multiple_occurrences(true)
+                .required(false),
         )
         .arg(
             Arg::with_name("input")
-                .help("File to search in. This is optional. If omitted, takes input from STDIN")
+                .help("Files to search in. This is optional. If omitted, takes input from STDIN. When more than one file is given, each line of output is prefixed with the file name")
                 .takes_value(true)
+                .multiple_values(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("query")
+                .help("Searches for lines satisfying QUERY, which combines terms with AND, OR and NOT, and parentheses, like 'error AND (db OR cache) AND NOT retry'. Each term is a regular expression, which can be quoted with \" to contain spaces or parentheses. Terms next to each other must all match. With --color, each term is highlighted in a different color")
+                .long("query")
+                .value_name("QUERY")
+                .takes_value(true)
+                .validator(|query| Query::parse(query, &Flags::default()).map(|_| ()))
+                .conflicts_with_all(&["regexp", "multiline"])
                 .required(false),
         )
         .arg(
@@ -32,6 +57,21 @@ impl<'cli> Cli<'cli> {
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("count_matches")
+                .help("Supresses normal output and instead prints the number of matches, counting every match on a line rather than matching lines. Same as --only-matching with --count")
+                .long("count-matches")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("only_matching")
+                .help("Prints only the matched parts of matching lines, each on a line of its own")
+                .short('o')
+                .long("only-matching")
+                .takes_value(false)
+                .required(false),
+        )
         .arg(
             Arg::with_name("line_number")
             .help("Prefixes each line of output with the 1-based line number within its input file")
@@ -40,11 +80,102 @@ impl<'cli> Cli<'cli> {
             .takes_value(false)
             .required(false)
         )
+        .arg(
+            Arg::with_name("column")
+            .help("Prefixes each matching line of output with the 1-based column number of the first match within the line")
+            .long("column")
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("byte_offset")
+            .help("Prefixes each line of output with the 0-based byte offset of the line within its input file")
+            .short('b')
+            .long("byte-offset")
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("vimgrep")
+            .help("Prints every match as path:line:column:text, the format read by the quickfix lists of editors like Vim. A line with several matches is printed once for each of them")
+            .long("vimgrep")
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("json")
+            .help("Prints the results as JSON Lines: a begin event for each file, a match or context event for each line, an end event with the statistics of each file, or an error event for a file that can't be read through, and a final summary")
+            .long("json")
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("stats")
+            .help("Prints statistics about the search at the end: the number of matching lines, of matches, of files searched and containing matches, of bytes searched and the time it took. When several files are searched, the statistics of each of them are printed as well")
+            .long("stats")
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("replace")
+            .help("Prints matching lines with every match replaced by TEMPLATE. In TEMPLATE, $0 stands for the whole match, $1, $2 and so on for the respective capture group and ${name} for the capture group named name. Use $$ for a literal $. With --multiline, it's only applied with --in-place")
+            .long("replace")
+            .short('r')
+            .value_name("TEMPLATE")
+            .takes_value(true)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("in_place")
+            .help("Rewrites the input files with the replacements of --replace applied, instead of printing them. With --query, only the lines satisfying it are rewritten. Files are replaced atomically and keep their permissions")
+            .long("in-place")
+            .requires("replace")
+            .conflicts_with("invert_match")
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("dry_run")
+            .help("With --in-place, leaves the files untouched and prints a unified diff of what would change instead")
+            .long("dry-run")
+            .requires("in_place")
+            .takes_value(false)
+            .required(false)
+        )
         .arg(
             Arg::with_name("color")
-            .help("Highlights the matched terms on every line with red color")
+            .help("Highlights the matched terms on every line in the match color, along with line numbers, file names and group separators. WHEN is auto, always or never. With auto, output is colored only when it goes to a terminal and the NO_COLOR environment variable isn't set. --color on its own means --color=always")
             .long("color")
-            .takes_value(false)
+            .value_name("WHEN")
+            .takes_value(true)
+            .possible_values(["auto", "always", "never"])
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("always")
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("colors")
+            .help("Sets the colors of a part of the output. SPEC is PART:fg:COLOR, PART:bg:COLOR, PART:style:STYLE or PART:none, where PART is match, line, separator or path. COLOR is a name like yellow, a number from 0 to 255, or a truecolor value like 255,128,0 or #ff8000. STYLE is bold, dimmed, italic or underline, or the same prefixed with no to turn it off. Can be given more than once. The GREP_COLORS environment variable is read too, with its ms, ln, se and fn capabilities")
+            .long("colors")
+            .value_name("SPEC")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .validator(|spec| ColorScheme::default().apply_spec(spec))
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("hyperlink_format")
+            .help("Turns file names and line numbers into hyperlinks that terminals supporting OSC 8 can open. FORMAT is one of the presets default, file, vscode, idea or none, or a URL template where {path} stands for the absolute path of the file, {line} for the line number and {column} for the column. Only used when the output is colored")
+            .long("hyperlink-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .validator(|format| match hyperlink_template(format) {
+                Some(template) if !template.contains("{path}") => {
+                    Err("a custom format must contain {path}".to_string())
+                }
+                _ => Ok(()),
+            })
             .required(false)
         )
         .arg(
@@ -55,6 +186,67 @@ impl<'cli> Cli<'cli> {
             .takes_value(false)
             .required(false)
         )
+        .arg(
+            Arg::with_name("fixed_strings")
+            .help("Takes the patterns literally rather than as regular expressions, so that characters like ( and * match themselves")
+            .short('F')
+            .long("fixed-strings")
+            .takes_value(false)
+            .overrides_with_all(&["basic_regexp", "extended_regexp"])
+            .conflicts_with_all(&["query", "fuzzy"])
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("basic_regexp")
+            .help("Reads the patterns as POSIX basic regular expressions, like grep does by default, where \\(, \\), \\{, \\}, \\|, \\+ and \\? are the metacharacters. Bracket expressions like [[:alpha:]] are read as in POSIX")
+            .short('G')
+            .long("basic-regexp")
+            .takes_value(false)
+            .overrides_with_all(&["fixed_strings", "extended_regexp"])
+            .conflicts_with_all(&["query", "fuzzy"])
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("extended_regexp")
+            .help("Reads the patterns as POSIX extended regular expressions, like grep -E does. Bracket expressions like [[:alpha:]] are read as in POSIX")
+            .short('E')
+            .long("extended-regexp")
+            .takes_value(false)
+            .overrides_with_all(&["fixed_strings", "basic_regexp"])
+            .conflicts_with_all(&["query", "fuzzy"])
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("fuzzy")
+            .help("Takes the patterns literally and matches them with up to K edits, each inserting, deleting or replacing a character, to find text with typos. Each pattern must be longer than K characters")
+            .long("fuzzy")
+            .value_name("K")
+            .takes_value(true)
+            .validator(|edits| edits.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .conflicts_with("query")
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("normalize")
+            .help("Matches text that's the same once both the patterns and the input are in the Unicode normalization form FORM, nfc or nfkc. nfkc, the default, also folds compatibility characters like ligatures and full-width letters, and typographic quotes like ’ into ASCII ones. Matches are still printed as they're written in the input")
+            .long("normalize")
+            .value_name("FORM")
+            .takes_value(true)
+            .possible_values(["nfc", "nfkc"])
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("nfkc")
+            .conflicts_with_all(&["replace", "in_place", "multiline"])
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("ignore_accents")
+            .help("Ignores accents and other combining marks in patterns and input data, so that cafe matches café. Matches are still printed as they're written in the input")
+            .long("ignore-accents")
+            .takes_value(false)
+            .conflicts_with_all(&["replace", "in_place", "multiline"])
+            .required(false)
+        )
         .arg(
             Arg::with_name("invert_match")
             .help("Inverts the sense of matching, to select non-matching lines")
@@ -63,6 +255,53 @@ impl<'cli> Cli<'cli> {
             .takes_value(false)
             .required(false)
         )
+        .arg(
+            Arg::with_name("multiline")
+            .help("Searches the whole input at once, so that matches can span multiple lines. Prints every line a match spans")
+            .long("multiline")
+            .short('U')
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("null_data")
+            .help("Treats input and output data as sequences of records, each terminated by a zero byte (the ASCII NUL character) instead of a newline")
+            .long("null-data")
+            .short('z')
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("crlf")
+            .help("Ends each line of output with CRLF (\\r\\n), like the lines of files with Windows line endings. A \\r before the newline ending a line of input is always stripped before matching, with or without this option")
+            .long("crlf")
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("files_with_matches")
+            .help("Supresses normal output and instead prints the name of each file containing a match")
+            .long("files-with-matches")
+            .short('l')
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("null")
+            .help("Outputs a zero byte (the ASCII NUL character) instead of the character that normally follows a file name, so that file names with spaces, colons or newlines can be handled by tools like `xargs -0`")
+            .long("null")
+            .short('Z')
+            .takes_value(false)
+            .required(false)
+        )
+        .arg(
+            Arg::with_name("no_messages")
+            .help("Suppresses the error messages about files that can't be opened or read. The other files are still searched, and grab still exits with status 2")
+            .long("no-messages")
+            .short('s')
+            .takes_value(false)
+            .required(false)
+        )
         .arg(
             Arg::with_name("after_context")
             .help("Prints NUM lines of trailing context after the matching lines. Each group of match and its context is separated by a separator as described by the --group-separator option")
@@ -87,6 +326,40 @@ impl<'cli> Cli<'cli> {
             .value_name("NUM")
             .takes_value(true)
             .required(false)
+        ).arg(
+            Arg::with_name("paragraph")
+            .help("Prints the whole paragraph containing each matching line as context. Paragraphs are delimited by blank lines, unless --block-start is given")
+            .long("paragraph")
+            .takes_value(false)
+            .required(false)
+        ).arg(
+            Arg::with_name("block_start")
+            .help("Prints the whole block containing each matching line as context, where a new block starts at every line matching REGEX. Implies --paragraph")
+            .long("block-start")
+            .value_name("REGEX")
+            .takes_value(true)
+            .required(false)
+        ).arg(
+            Arg::with_name("near")
+            .help("Searches for places where a match of B is within --within lines of a match of A, in either order. The lines from one match to the other are printed as a group, separated from other groups as described by the --group-separator option")
+            .long("near")
+            .value_names(&["A", "B"])
+            .number_of_values(2)
+            .requires("within")
+            .conflicts_with_all(&[
+                "regexp", "query", "multiline", "invert_match", "after_context", "before_context",
+                "context", "paragraph", "block_start", "count", "count_matches",
+                "files_with_matches", "vimgrep", "json", "in_place",
+            ])
+            .required(false)
+        ).arg(
+            Arg::with_name("within")
+            .help("The greatest number of lines between the matches of --near")
+            .long("within")
+            .value_name("NUM")
+            .takes_value(true)
+            .requires("near")
+            .required(false)
         ).arg(
             Arg::with_name("group_separator")
             .help("Use SEP as a group separator. By default SEP is a triple hyphen (---)")
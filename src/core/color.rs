@@ -0,0 +1,322 @@
+use crate::core::flag::Flags;
+
+/// Contains the parts of the output that can be colored, like group separators and matches.
+/// Their styles are set by the `ColorScheme` of `Flags`.
+pub(crate) enum Colors {
+    /// Matched text. Each pattern and capture group is numbered, and
+    /// its color is taken from `PALETTE`, starting with the match color, red by default
+    Match(usize),
+    /// Line numbers, columns and byte offsets, green by default
+    LineNumber,
+    /// Group separators, blue by default
+    Separator,
+    /// File names, magenta by default
+    FileName,
+}
+
+impl Colors {
+    /// Colorizes a `pattern` with the style that the color scheme of `flags` sets
+    /// for this part of the output. If colors are turned off, `pattern` is returned as it is.
+    pub(crate) fn colorize_pattern(color: Self, pattern: &str, flags: &Flags) -> String {
+        if !flags.colorize {
+            return pattern.to_string();
+        }
+        let scheme = &flags.color_scheme;
+        match color {
+            Self::Match(0) => scheme.matched.paint(pattern),
+            Self::Match(n) => PALETTE[(n - 1) % PALETTE.len()].paint(pattern),
+            Self::LineNumber => scheme.line_number.paint(pattern),
+            Self::Separator => scheme.separator.paint(pattern),
+            Self::FileName => scheme.file_name.paint(pattern),
+        }
+    }
+}
+
+/// Wraps `text` in the OSC 8 escape sequences that make it a hyperlink to `url`
+/// in the terminals supporting them. Other terminals show `text` as it is.
+pub(crate) fn hyperlink(url: &str, text: &str) -> String {
+    format!("\u{1b}]8;;{}\u{1b}\\{}\u{1b}]8;;\u{1b}\\", url, text)
+}
+
+/// The colors that patterns and capture groups after the first one rotate through.
+const PALETTE: [Style; 5] = [
+    Style::fg(Color::Basic(3)),
+    Style::fg(Color::Basic(6)),
+    Style::fg(Color::Basic(2)),
+    Style::fg(Color::Basic(5)),
+    Style::fg(Color::Basic(4)),
+];
+
+/// A terminal color, used for the foreground or the background of a `Style`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Color {
+    /// One of the 8 basic colors, numbered from black (0) to white (7)
+    Basic(u8),
+    /// One of the 256 colors of the extended palette
+    Fixed(u8),
+    /// A 24-bit truecolor value
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// Parses a color given by name, like `red`, by its number in the
+    /// 256-color palette, like `208`, or as a truecolor value, like `255,128,0` or `#ff8000`.
+    fn parse(value: &str) -> Result<Self, String> {
+        const NAMES: [&str; 8] = [
+            "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+        ];
+        if let Some(i) = NAMES.iter().position(|&name| name == value) {
+            return Ok(Color::Basic(i as u8));
+        }
+        if let Ok(n) = value.parse::<u8>() {
+            return Ok(Color::Fixed(n));
+        }
+        if let Some(hex) = value.strip_prefix('#') {
+            let channel = |i: usize| {
+                hex.get(i..i + 2)
+                    .and_then(|c| u8::from_str_radix(c, 16).ok())
+            };
+            if let (6, Some(r), Some(g), Some(b)) = (hex.len(), channel(0), channel(2), channel(4))
+            {
+                return Ok(Color::Rgb(r, g, b));
+            }
+        } else if let [r, g, b] = value.split(',').collect::<Vec<&str>>()[..] {
+            if let (Ok(r), Ok(g), Ok(b)) = (r.parse(), g.parse(), b.parse()) {
+                return Ok(Color::Rgb(r, g, b));
+            }
+        }
+        Err(format!(
+            "unknown color '{}': expected a name like red, a number from 0 to 255, r,g,b or #rrggbb",
+            value
+        ))
+    }
+
+    /// Appends the SGR parameters that select this color to `codes`.
+    /// `base` is 30 for the foreground and 40 for the background.
+    fn push_codes(self, base: u8, codes: &mut Vec<String>) {
+        match self {
+            Color::Basic(n) => codes.push(format!("{}", base + n)),
+            Color::Fixed(n) => codes.push(format!("{};5;{}", base + 8, n)),
+            Color::Rgb(r, g, b) => codes.push(format!("{};2;{};{};{}", base + 8, r, g, b)),
+        }
+    }
+}
+
+/// The colors and text attributes applied to a part of the output.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Style {
+    pub(crate) fg: Option<Color>,
+    pub(crate) bg: Option<Color>,
+    pub(crate) bold: bool,
+    pub(crate) dimmed: bool,
+    pub(crate) italic: bool,
+    pub(crate) underline: bool,
+}
+
+impl Style {
+    /// Returns a style that only sets the foreground color.
+    const fn fg(color: Color) -> Self {
+        Style {
+            fg: Some(color),
+            bg: None,
+            bold: false,
+            dimmed: false,
+            italic: false,
+            underline: false,
+        }
+    }
+
+    /// Wraps `text` in the escape sequences that turn this style on and back off.
+    /// Only the attributes this style sets are turned off afterwards, so a plain
+    /// red style ends with the "default foreground" sequence rather than a full reset.
+    pub(crate) fn paint(&self, text: &str) -> String {
+        let mut on: Vec<String> = Vec::new();
+        let mut off: Vec<String> = Vec::new();
+        if self.bold {
+            on.push("1".to_string());
+        }
+        if self.dimmed {
+            on.push("2".to_string());
+        }
+        if self.bold || self.dimmed {
+            off.push("22".to_string());
+        }
+        if self.italic {
+            on.push("3".to_string());
+            off.push("23".to_string());
+        }
+        if self.underline {
+            on.push("4".to_string());
+            off.push("24".to_string());
+        }
+        if let Some(fg) = self.fg {
+            fg.push_codes(30, &mut on);
+            off.push("39".to_string());
+        }
+        if let Some(bg) = self.bg {
+            bg.push_codes(40, &mut on);
+            off.push("49".to_string());
+        }
+        if on.is_empty() {
+            return text.to_string();
+        }
+
+        format!("\u{1b}[{}m{}\u{1b}[{}m", on.join(";"), text, off.join(";"))
+    }
+
+    /// Parses a list of SGR parameters, like the `01;31` values of `GREP_COLORS`.
+    /// Parameters that don't set a color or a supported attribute are ignored.
+    fn from_sgr(sgr: &str) -> Self {
+        let mut style = Style::default();
+        let codes: Vec<u8> = sgr.split(';').filter_map(|c| c.parse().ok()).collect();
+        let mut i = 0;
+        while i < codes.len() {
+            // Extended colors take their value from the parameters that follow
+            let extended = |i: usize| match codes.get(i + 1..) {
+                Some([5, n, ..]) => Some((Color::Fixed(*n), 2)),
+                Some([2, r, g, b, ..]) => Some((Color::Rgb(*r, *g, *b), 4)),
+                _ => None,
+            };
+            match codes[i] {
+                0 => style = Style::default(),
+                1 => style.bold = true,
+                2 => style.dimmed = true,
+                3 => style.italic = true,
+                4 => style.underline = true,
+                n @ 30..=37 => style.fg = Some(Color::Basic(n - 30)),
+                n @ 40..=47 => style.bg = Some(Color::Basic(n - 40)),
+                n @ 90..=97 => style.fg = Some(Color::Fixed(n - 90 + 8)),
+                n @ 100..=107 => style.bg = Some(Color::Fixed(n - 100 + 8)),
+                38 | 48 => {
+                    if let Some((color, skip)) = extended(i) {
+                        match codes[i] {
+                            38 => style.fg = Some(color),
+                            _ => style.bg = Some(color),
+                        }
+                        i += skip;
+                    }
+                }
+                _ => (),
+            }
+            i += 1;
+        }
+        style
+    }
+}
+
+/// The styles of each part of the output that can be colored.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ColorScheme {
+    pub(crate) matched: Style,
+    pub(crate) line_number: Style,
+    pub(crate) separator: Style,
+    pub(crate) file_name: Style,
+}
+
+impl Default for ColorScheme {
+    fn default() -> Self {
+        ColorScheme {
+            matched: Style::fg(Color::Basic(1)),
+            line_number: Style::fg(Color::Basic(2)),
+            separator: Style::fg(Color::Basic(4)),
+            file_name: Style::fg(Color::Basic(5)),
+        }
+    }
+}
+
+impl ColorScheme {
+    /// Returns the style of the output part named `part` in a color specification.
+    fn style_mut(&mut self, part: &str) -> Result<&mut Style, String> {
+        match part {
+            "match" => Ok(&mut self.matched),
+            "line" => Ok(&mut self.line_number),
+            "separator" => Ok(&mut self.separator),
+            "path" => Ok(&mut self.file_name),
+            _ => Err(format!(
+                "unknown output part '{}': expected match, line, separator or path",
+                part
+            )),
+        }
+    }
+
+    /// Applies a color specification as given to `--colors`, like `match:fg:yellow`,
+    /// `line:bg:236`, `path:fg:255,128,0` or `match:style:bold`. `match:none`
+    /// removes every color and attribute from matches.
+    pub(crate) fn apply_spec(&mut self, spec: &str) -> Result<(), String> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        match fields[..] {
+            [part, "none"] => *self.style_mut(part)? = Style::default(),
+            [part, "fg", value] => self.style_mut(part)?.fg = Some(Color::parse(value)?),
+            [part, "bg", value] => self.style_mut(part)?.bg = Some(Color::parse(value)?),
+            [part, "style", value] => {
+                let style = self.style_mut(part)?;
+                match value {
+                    "bold" => style.bold = true,
+                    "nobold" => style.bold = false,
+                    "dimmed" => style.dimmed = true,
+                    "nodimmed" => style.dimmed = false,
+                    "italic" => style.italic = true,
+                    "noitalic" => style.italic = false,
+                    "underline" => style.underline = true,
+                    "nounderline" => style.underline = false,
+                    _ => {
+                        return Err(format!(
+                            "unknown style '{}': expected bold, dimmed, italic or underline, optionally prefixed with no",
+                            value
+                        ))
+                    }
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "invalid color specification '{}': expected PART:fg:COLOR, PART:bg:COLOR, PART:style:STYLE or PART:none",
+                    spec
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the capabilities of a `GREP_COLORS` environment variable, like
+    /// `ms=01;31:ln=32:se=36:fn=35`. Matches are set by `ms` or `mt`, line numbers
+    /// by `ln`, separators by `se` and file names by `fn`. Other capabilities are ignored.
+    pub(crate) fn apply_grep_colors(&mut self, grep_colors: &str) {
+        for capability in grep_colors.split(':') {
+            let (name, sgr) = match capability.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let style = Style::from_sgr(sgr);
+            match name {
+                "ms" | "mt" => self.matched = style,
+                "ln" => self.line_number = style,
+                "se" => self.separator = style,
+                "fn" => self.file_name = style,
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_specs_and_grep_colors() {
+        let mut scheme = ColorScheme::default();
+        scheme.apply_grep_colors("ms=01;31:ln=38;5;208:se=36");
+        scheme.apply_spec("match:fg:#ff8000").unwrap();
+        scheme.apply_spec("match:style:underline").unwrap();
+        scheme.apply_spec("path:none").unwrap();
+        assert_eq!(
+            scheme.matched.paint("soul"),
+            "\u{1b}[1;4;38;2;255;128;0msoul\u{1b}[22;24;39m"
+        );
+        assert_eq!(scheme.line_number.paint("3"), "\u{1b}[38;5;208m3\u{1b}[39m");
+        assert_eq!(scheme.separator.paint("---"), "\u{1b}[36m---\u{1b}[39m");
+        assert_eq!(scheme.file_name.paint("pessoa.txt"), "pessoa.txt");
+        assert!(scheme.apply_spec("match:fg:reddish").is_err());
+        assert!(scheme.apply_spec("title:fg:red").is_err());
+    }
+}
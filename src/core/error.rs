@@ -1,12 +1,13 @@
 use std::{fmt::Display, io, num};
 
-/// Writes to the standard error stream and terminates the current process.
+/// Writes to the standard error stream and terminates the current process with
+/// exit code 2, like grep does on errors.
 #[macro_export]
 macro_rules! fatal {
     ($($tt:tt)*) => {{
         use std::io::Write;
         writeln!(&mut ::std::io::stderr(), $($tt)*).unwrap();
-        ::std::process::exit(1)
+        ::std::process::exit(2)
     }}
 }
 
@@ -15,10 +16,136 @@ macro_rules! fatal {
 pub enum CliError {
     /// I/O error
     Io(io::Error),
-    /// Error in parsing context number
-    Parse(num::ParseIntError),
+    /// I/O error while doing `operation` on the file at `path`
+    File {
+        path: String,
+        operation: &'static str,
+        error: io::Error,
+    },
+    /// Error in parsing the number given to `flag`
+    Flag {
+        flag: &'static str,
+        value: String,
+        error: num::ParseIntError,
+    },
     /// Error in compiling regex
     Regex(regex::Error),
+    /// Syntax error in a pattern
+    Pattern(PatternError),
+    /// The number of edits given to `--fuzzy` is as large as `pattern` is long
+    FuzzyEdits { edits: usize, pattern: String },
+}
+
+/// A syntax error in a pattern, shown with a caret under the part of the
+/// pattern it's about, and advice on the usual mistake behind it.
+#[derive(Debug)]
+pub struct PatternError {
+    pattern: String,
+    message: String,
+    /// The character columns of the pattern that the error is about, starting from 0
+    columns: (usize, usize),
+    hint: Option<String>,
+}
+
+impl PatternError {
+    /// Describes `error`, found while parsing `pattern` or a rewriting of it, like its
+    /// translation from the POSIX syntax. `original_span` maps byte ranges of what was
+    /// parsed back to `pattern`.
+    pub(crate) fn mapped(
+        pattern: &str,
+        error: &regex_syntax::Error,
+        original_span: impl Fn((usize, usize)) -> (usize, usize),
+    ) -> Self {
+        let (message, span) = match error {
+            regex_syntax::Error::Parse(err) => (err.kind().to_string(), *err.span()),
+            regex_syntax::Error::Translate(err) => (err.kind().to_string(), *err.span()),
+            err => (
+                err.to_string(),
+                regex_syntax::ast::Span::splat(regex_syntax::ast::Position::new(0, 1, 1)),
+            ),
+        };
+        let (start, end) = original_span((span.start.offset, span.end.offset));
+        let column = |offset: usize| pattern[..offset.min(pattern.len())].chars().count();
+        Self::at(pattern, message, (column(start), column(end)), hint(error))
+    }
+
+    /// Describes a syntax error in `pattern` explained by `message`, about its characters
+    /// in the range `columns`, with advice on the usual mistake behind it, if there's one.
+    pub(crate) fn at(
+        pattern: &str,
+        message: String,
+        columns: (usize, usize),
+        hint: Option<&str>,
+    ) -> Self {
+        let mut hint = hint.map(String::from);
+        // A pattern without escapes or extensions was likely meant to be taken literally
+        if !pattern.contains('\\') && !pattern.contains("(?") {
+            let fixed_strings = "use -F to search for the pattern as it's written";
+            hint = Some(match hint {
+                Some(hint) => format!("{}, or {}", hint, fixed_strings),
+                None => fixed_strings.to_string(),
+            });
+        }
+        PatternError {
+            pattern: pattern.to_string(),
+            message,
+            columns,
+            hint,
+        }
+    }
+
+    /// Drops the advice on the usual mistake behind the error.
+    pub(crate) fn without_hint(self) -> Self {
+        PatternError { hint: None, ..self }
+    }
+}
+
+/// Advice on back-references, which the `regex` crate and POSIX translation both reject.
+pub(crate) const BACKREFERENCE_HINT: &str =
+    "back-references like \\1 need a backtracking engine, like the one of grep -P, which grab doesn't have";
+
+/// Returns advice on the usual mistake behind `error`, if there's one.
+fn hint(error: &regex_syntax::Error) -> Option<&'static str> {
+    use regex_syntax::ast::ErrorKind;
+
+    let kind = match error {
+        regex_syntax::Error::Parse(err) => err.kind(),
+        _ => return None,
+    };
+    Some(match kind {
+        ErrorKind::GroupUnclosed => "to match a literal (, escape it as \\(",
+        ErrorKind::GroupUnopened => "to match a literal ), escape it as \\)",
+        ErrorKind::ClassUnclosed => "to match a literal [, escape it as \\[",
+        ErrorKind::RepetitionMissing => "to match a literal *, + or ?, escape it with a backslash, like \\*",
+        ErrorKind::RepetitionCountUnclosed
+        | ErrorKind::RepetitionCountDecimalEmpty
+        | ErrorKind::RepetitionCountInvalid => "to match a literal {, escape it as \\{",
+        ErrorKind::EscapeUnrecognized => "to match a literal backslash, escape it as \\\\",
+        ErrorKind::UnsupportedLookAround => {
+            "look-around like (?<=...) or (?=...) needs a backtracking engine, like the one of grep -P, \
+             which grab doesn't have: match the text around as well instead"
+        }
+        ErrorKind::UnsupportedBackreference => BACKREFERENCE_HINT,
+        _ => return None,
+    })
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (start, end) = self.columns;
+        writeln!(f, "invalid pattern: {}", self.message)?;
+        writeln!(f, "    {}", self.pattern)?;
+        write!(
+            f,
+            "    {}{}",
+            " ".repeat(start),
+            "^".repeat((end - start).max(1))
+        )?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\nhint: {}", hint)?;
+        }
+        Ok(())
+    }
 }
 
 impl From<io::Error> for CliError {
@@ -27,9 +154,15 @@ impl From<io::Error> for CliError {
     }
 }
 
-impl From<num::ParseIntError> for CliError {
-    fn from(err: num::ParseIntError) -> Self {
-        CliError::Parse(err)
+impl CliError {
+    /// Returns an error about `error`, which happened while doing `operation`,
+    /// like `open` or `read`, on the file at `path`.
+    pub(crate) fn file(path: &str, operation: &'static str, error: io::Error) -> Self {
+        CliError::File {
+            path: path.to_string(),
+            operation,
+            error,
+        }
     }
 }
 
@@ -43,8 +176,113 @@ impl Display for CliError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
             CliError::Io(ref err) => err.fmt(f),
-            CliError::Parse(ref err) => err.fmt(f),
+            CliError::File {
+                ref path,
+                operation,
+                ref error,
+            } => write!(f, "{}: failed to {}: {}", path, operation, error),
+            CliError::Flag {
+                flag,
+                ref value,
+                ref error,
+            } => write!(f, "invalid value '{}' for {}: {}", value, flag, error),
             CliError::Regex(ref err) => err.fmt(f),
+            CliError::Pattern(ref err) => err.fmt(f),
+            CliError::FuzzyEdits { edits, ref pattern } => write!(
+                f,
+                "--fuzzy {} allows as many edits as '{}' has characters, so it would match anywhere: \
+                 try fewer edits or a longer pattern",
+                edits, pattern
+            ),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::flag::Flags;
+    use crate::core::posix::PosixSyntax;
+    use crate::core::utils::{compile_patterns, parse_context_number};
+
+    fn pattern_error(pattern: &str) -> String {
+        let err = regex_syntax::Parser::new().parse(pattern).unwrap_err();
+        PatternError::mapped(pattern, &err, |span| span).to_string()
+    }
+
+    #[test]
+    fn errors_name_the_file_and_flag() {
+        let err = std::fs::File::open("missing.txt").unwrap_err();
+        assert_eq!(
+            CliError::file("missing.txt", "open", err).to_string(),
+            "missing.txt: failed to open: No such file or directory (os error 2)"
+        );
+        assert_eq!(
+            parse_context_number("abc", "--context")
+                .unwrap_err()
+                .to_string(),
+            "invalid value 'abc' for --context: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn pattern_errors_point_at_the_mistake() {
+        assert_eq!(
+            pattern_error("price: €5 (EUR"),
+            "invalid pattern: unclosed group
+    price: €5 (EUR
+              ^
+hint: to match a literal (, escape it as \\(, or use -F to search for the pattern as it's written"
+        );
+        assert_eq!(
+            pattern_error("(?<!un)written"),
+            "invalid pattern: look-around, including look-ahead and look-behind, is not supported
+    (?<!un)written
+    ^^^^
+hint: look-around like (?<=...) or (?=...) needs a backtracking engine, like the one of grep -P, \
+             which grab doesn't have: match the text around as well instead"
+        );
+        assert_eq!(
+            pattern_error(r"\p{Elvish}"),
+            "invalid pattern: Unicode property not found
+    \\p{Elvish}
+    ^^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn rewritten_pattern_errors_point_at_the_pattern_as_written() {
+        let compile_error = |patterns: &[&str], flags: &Flags| {
+            compile_patterns(patterns, flags).unwrap_err().to_string()
+        };
+        let basic = Flags {
+            syntax: Some(PosixSyntax::Basic),
+            ..Flags::default()
+        };
+        assert_eq!(
+            compile_error(&[r"a\{2"], &basic),
+            "invalid pattern: unmatched \\{
+    a\\{2
+     ^^
+hint: to match a literal {, write it without a backslash"
+        );
+        assert_eq!(
+            compile_error(&["[a-z]", r"\.\{2\}\(x"], &basic),
+            "invalid pattern: unclosed group
+    \\.\\{2\\}\\(x
+           ^^"
+        );
+        let accents = Flags {
+            syntax: Some(PosixSyntax::Extended),
+            ignore_accents: true,
+            ..Flags::default()
+        };
+        assert_eq!(
+            compile_error(&["café{2}(x"], &accents),
+            "invalid pattern: unclosed group
+    café{2}(x
+           ^
+hint: to match a literal (, escape it as \\(, or use -F to search for the pattern as it's written"
+        );
+    }
+}
@@ -1,25 +1,120 @@
+use crate::core::color::ColorScheme;
+use crate::core::normalize::Normalization;
+use crate::core::posix::PosixSyntax;
+use crate::core::query::Query;
 use clap::ArgMatches;
+use std::env;
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal};
+
+/// Decides whether output is colored, based on the `--color` mode.
+///
+/// `auto` colors the output only when it goes to a terminal, as told by `is_terminal`,
+/// and `no_color`, the value of the `NO_COLOR` environment variable, isn't set or is empty.
+fn colors_enabled(mode: &str, is_terminal: bool, no_color: Option<&OsStr>) -> bool {
+    match mode {
+        "always" => true,
+        "never" => false,
+        _ => is_terminal && no_color.is_none_or(OsStr::is_empty),
+    }
+}
+
+/// Decides whether output is colored as described by `colors_enabled`, for the
+/// standard output stream and the environment of the process.
+fn use_colors(mode: &str) -> bool {
+    colors_enabled(
+        mode,
+        io::stdout().is_terminal(),
+        env::var_os("NO_COLOR").as_deref(),
+    )
+}
+
+/// Returns the URL template of a `--hyperlink-format` preset, or the
+/// format itself when it's a custom template. `none` turns hyperlinks off.
+pub(crate) fn hyperlink_template(format: &str) -> Option<String> {
+    match format {
+        "none" => None,
+        "default" | "file" => Some("file://{path}".to_string()),
+        "vscode" => Some("vscode://file{path}:{line}:{column}".to_string()),
+        "idea" => Some("idea://open?file={path}&line={line}".to_string()),
+        custom => Some(custom.to_string()),
+    }
+}
 
 /// Struct representting the argument flags.
 ///
 /// # Respective flags represented by the fields:
 /// ```
 /// --count, -c
+/// --count-matches
+/// --only-matching, -o
 /// --line-number, -n
-/// --color
+/// --color=auto|always|never
+/// --colors
+/// --hyperlink-format
 /// --ignore-case, -i
+/// --fixed-strings, -F
+/// --basic-regexp, -G
+/// --extended-regexp, -E
+/// --fuzzy
+/// --normalize=nfc|nfkc
+/// --ignore-accents
+/// --query
 /// --invert-match, -v
 /// --after-context, -A,
 /// --before-context, -B,
 /// --context, -C,
+/// --paragraph,
+/// --block-start,
+/// --near,
+/// --within,
+/// --multiline, -U
+/// --null-data, -z
+/// --crlf
+/// --files-with-matches, -l
+/// --null, -Z
+/// --no-messages, -s
+/// --column
+/// --byte-offset, -b
+/// --vimgrep
+/// --json
+/// --stats
+/// --replace, -r
+/// --in-place
+/// --dry-run
 /// ```
 #[derive(Debug, Default)]
 pub struct Flags {
     pub count: bool,
+    pub count_matches: bool,
+    pub only_matching: bool,
     pub line_number: bool,
     pub colorize: bool,
+    pub color_scheme: ColorScheme,
+    pub hyperlink_format: Option<String>,
     pub ignore_case: bool,
+    pub fixed_strings: bool,
+    pub syntax: Option<PosixSyntax>,
+    pub fuzzy: Option<usize>,
+    pub normalize: Option<Normalization>,
+    pub ignore_accents: bool,
+    pub query: Option<Query>,
     pub invert_match: bool,
+    pub multiline: bool,
+    pub null_data: bool,
+    pub crlf: bool,
+    pub files_with_matches: bool,
+    pub null: bool,
+    pub no_messages: bool,
+    pub with_filename: bool,
+    pub column: bool,
+    pub byte_offset: bool,
+    pub vimgrep: bool,
+    pub json: bool,
+    pub stats: bool,
+    pub replace: Option<String>,
+    pub in_place: bool,
+    pub dry_run: bool,
 }
 
 impl Flags {
@@ -31,11 +126,108 @@ impl Flags {
         let mut flags = Self::new();
 
         flags.count = a.is_present("count");
+        flags.only_matching = a.is_present("only_matching");
+        // Like in grep, `-o -c` counts every match rather than matching lines
+        flags.count_matches = a.is_present("count_matches") || (flags.only_matching && flags.count);
         flags.line_number = a.is_present("line_number");
-        flags.colorize = a.is_present("color");
+        flags.colorize = use_colors(a.value_of("color").unwrap_or("auto"));
+        // `--colors` specifications take precedence over `GREP_COLORS`
+        if let Ok(grep_colors) = env::var("GREP_COLORS") {
+            flags.color_scheme.apply_grep_colors(&grep_colors);
+        }
+        for spec in a.values_of("colors").into_iter().flatten() {
+            // Already checked by the validator of `--colors`
+            flags.color_scheme.apply_spec(spec).unwrap();
+        }
+        // Terminals that don't understand colors are unlikely to understand hyperlinks
+        if flags.colorize {
+            flags.hyperlink_format = a.value_of("hyperlink_format").and_then(hyperlink_template);
+        }
         flags.ignore_case = a.is_present("ignore_case");
+        flags.normalize = match a.value_of("normalize") {
+            Some("nfc") => Some(Normalization::Nfc),
+            Some(_) => Some(Normalization::Nfkc),
+            None => None,
+        };
+        flags.ignore_accents = a.is_present("ignore_accents");
+        // The last of `-F`, `-G` and `-E` wins, as they override each other
+        flags.fixed_strings = a.is_present("fixed_strings");
+        flags.syntax = if a.is_present("basic_regexp") {
+            Some(PosixSyntax::Basic)
+        } else if a.is_present("extended_regexp") {
+            Some(PosixSyntax::Extended)
+        } else {
+            None
+        };
+        // Already checked by the validator of `--fuzzy`
+        flags.fuzzy = a.value_of("fuzzy").map(|edits| edits.parse().unwrap());
+        // Checked by the validator of `--query` as it's written, but its terms could
+        // still fail to compile once they're folded
+        flags.query = a.value_of("query").map(|query| {
+            Query::parse(query, &flags).unwrap_or_else(|err| {
+                crate::fatal!("error: invalid value '{}' for --query: {}", query, err)
+            })
+        });
         flags.invert_match = a.is_present("invert_match");
+        flags.multiline = a.is_present("multiline");
+        flags.null_data = a.is_present("null_data");
+        flags.crlf = a.is_present("crlf");
+        flags.files_with_matches = a.is_present("files_with_matches");
+        flags.null = a.is_present("null");
+        flags.no_messages = a.is_present("no_messages");
+        flags.column = a.is_present("column");
+        flags.byte_offset = a.is_present("byte_offset");
+        flags.vimgrep = a.is_present("vimgrep");
+        flags.json = a.is_present("json");
+        flags.stats = a.is_present("stats");
+        flags.replace = a.value_of("replace").map(String::from);
+        flags.in_place = a.is_present("in_place");
+        flags.dry_run = a.is_present("dry_run");
+        // Output lines are prefixed with file names when searching more than one file
+        // With `--regexp`, `--query` or `--near`, the positional pattern is the first file to search in
+        let patterns_given = ["regexp", "query", "near"]
+            .iter()
+            .any(|arg| a.is_present(arg));
+        let files = a.values_of("input").map_or(0, |inputs| inputs.len())
+            + (patterns_given && a.is_present("pattern")) as usize;
+        flags.with_filename = files > 1;
 
         flags
     }
+
+    /// Returns the byte that ends each input record.
+    pub fn record_terminator(&self) -> u8 {
+        if self.null_data {
+            b'\0'
+        } else {
+            b'\n'
+        }
+    }
+
+    /// Returns the terminator written after each line of output.
+    /// With `--crlf`, lines end with `\r\n`.
+    pub fn line_terminator(&self) -> &'static str {
+        match (self.null_data, self.crlf) {
+            (true, _) => "\0",
+            (false, true) => "\r\n",
+            (false, false) => "\n",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_by_mode_terminal_and_no_color() {
+        let no_color = Some(OsStr::new("1"));
+        assert!(colors_enabled("always", false, no_color));
+        assert!(!colors_enabled("never", true, None));
+        assert!(colors_enabled("auto", true, None));
+        assert!(!colors_enabled("auto", false, None));
+        assert!(!colors_enabled("auto", true, no_color));
+        // An empty `NO_COLOR` counts as unset
+        assert!(colors_enabled("auto", true, Some(OsStr::new(""))));
+    }
 }
@@ -0,0 +1,303 @@
+use crate::core::error::CliError;
+use std::collections::HashMap;
+
+/// A set of bits, one for each character of a pattern, stored in 64-bit words
+/// with the bit of the first character lowest.
+type Bits = Vec<u64>;
+
+/// Returns the word at index `word` of `bits << 1`, with `start` shifted into the lowest bit.
+fn shifted(bits: &[u64], word: usize, start: bool) -> u64 {
+    let carry = match word {
+        0 => start as u64,
+        _ => bits[word - 1] >> 63,
+    };
+    (bits[word] << 1) | carry
+}
+
+/// A literal pattern searched for with `--fuzzy`, along with the bit masks of
+/// the characters in it, read forwards and backwards.
+#[derive(Debug, Clone)]
+struct FuzzyPattern {
+    len: usize,
+    /// The positions of each character within the pattern
+    masks: HashMap<char, Bits>,
+    /// The positions of each character within the reversed pattern
+    reversed_masks: HashMap<char, Bits>,
+}
+
+impl FuzzyPattern {
+    fn new(chars: &[char]) -> Self {
+        let words = chars.len().div_ceil(64);
+        let mut masks: HashMap<char, Bits> = HashMap::new();
+        let mut reversed_masks: HashMap<char, Bits> = HashMap::new();
+        for (i, &c) in chars.iter().enumerate() {
+            let j = chars.len() - 1 - i;
+            masks.entry(c).or_insert_with(|| vec![0; words])[i / 64] |= 1 << (i % 64);
+            reversed_masks.entry(c).or_insert_with(|| vec![0; words])[j / 64] |= 1 << (j % 64);
+        }
+        FuzzyPattern {
+            len: chars.len(),
+            masks,
+            reversed_masks,
+        }
+    }
+}
+
+/// The state of the bit-parallel search of a pattern with up to `edits` edits, as described
+/// by Wu and Manber. Bit `i` of `states[d]` is set when the first `i + 1` characters of the
+/// pattern match the text read so far, ending with its last character, with at most `d` edits.
+struct Search<'p> {
+    masks: &'p HashMap<char, Bits>,
+    len: usize,
+    states: Vec<Bits>,
+    /// The states before the last character read
+    previous: Vec<Bits>,
+    /// The number of characters read so far
+    read: usize,
+    /// Whether matches must start at the first character read, rather than anywhere
+    anchored: bool,
+}
+
+impl<'p> Search<'p> {
+    fn new(masks: &'p HashMap<char, Bits>, len: usize, edits: usize, anchored: bool) -> Self {
+        let words = len.div_ceil(64);
+        // With `d` edits, the first `d` characters of the pattern can be deleted before reading any text
+        let states: Vec<Bits> = (0..=edits)
+            .map(|d| {
+                let mut bits = vec![0; words];
+                (0..d.min(len)).for_each(|i| bits[i / 64] |= 1 << (i % 64));
+                bits
+            })
+            .collect();
+        Search {
+            masks,
+            len,
+            previous: states.clone(),
+            states,
+            read: 0,
+            anchored,
+        }
+    }
+
+    /// Returns whether the empty start of the pattern matches before the first `read`
+    /// characters of the text with at most `d` edits: always when unanchored, and
+    /// otherwise when every one of those characters can be an insertion.
+    fn starts_before(&self, read: usize, d: usize) -> bool {
+        !self.anchored || read <= d
+    }
+
+    /// Reads the character `c`, and returns the fewest edits with which the whole
+    /// pattern matches the text ending with it, if it's at most the number of edits allowed.
+    fn read(&mut self, c: char) -> Option<usize> {
+        std::mem::swap(&mut self.states, &mut self.previous);
+        let mask = self.masks.get(&c);
+        for d in 0..self.states.len() {
+            for w in 0..self.states[d].len() {
+                // The next character of the pattern matches `c`
+                let mut word = shifted(&self.previous[d], w, self.starts_before(self.read, d))
+                    & mask.map_or(0, |mask| mask[w]);
+                if d > 0 {
+                    // `c` replaces the next character of the pattern, the next character
+                    // of the pattern is deleted, or `c` is inserted
+                    word |= shifted(
+                        &self.previous[d - 1],
+                        w,
+                        self.starts_before(self.read, d - 1),
+                    ) | shifted(
+                        &self.states[d - 1],
+                        w,
+                        self.starts_before(self.read + 1, d - 1),
+                    ) | self.previous[d - 1][w];
+                }
+                self.states[d][w] = word;
+            }
+        }
+        self.read += 1;
+        let last = self.len - 1;
+        (0..self.states.len()).find(|&d| self.states[d][last / 64] & (1 << (last % 64)) != 0)
+    }
+}
+
+/// Searches for literal patterns with up to a number of insertions, deletions or
+/// substitutions of a character, like `agrep` does, as given with `--fuzzy`.
+#[derive(Debug, Clone)]
+pub(crate) struct FuzzyMatcher {
+    patterns: Vec<FuzzyPattern>,
+    edits: usize,
+    ignore_case: bool,
+}
+
+impl FuzzyMatcher {
+    /// Returns a matcher for `patterns` with up to `edits` edits. Fails for a pattern that
+    /// doesn't have more characters than that, since it would match anywhere.
+    pub(crate) fn new(
+        patterns: &[String],
+        edits: usize,
+        ignore_case: bool,
+    ) -> Result<Self, CliError> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                let chars: Vec<char> = pattern.chars().map(|c| fold_case(c, ignore_case)).collect();
+                match chars.len() > edits {
+                    true => Ok(FuzzyPattern::new(&chars)),
+                    false => Err(CliError::FuzzyEdits {
+                        edits,
+                        pattern: pattern.to_string(),
+                    }),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(FuzzyMatcher {
+            patterns,
+            edits,
+            ignore_case,
+        })
+    }
+
+    /// Returns whether any of the patterns matches somewhere in `text`.
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        self.patterns.iter().any(|pattern| {
+            let mut search = Search::new(&pattern.masks, pattern.len, self.edits, false);
+            text.chars()
+                .any(|c| search.read(fold_case(c, self.ignore_case)).is_some())
+        })
+    }
+
+    /// Returns the match of `pattern` in `chars`, the characters of a text along with
+    /// their byte offsets, that ends first after the character at index `from`, as
+    /// the range of indices of its characters.
+    ///
+    /// Where the pattern matches the text ending at several characters in a row, the
+    /// match ends at the one with the fewest edits, or the last of those, so that a match
+    /// isn't cut short. It then starts as far back as it can with as many edits.
+    fn find_at(
+        &self,
+        pattern: &FuzzyPattern,
+        chars: &[(usize, char)],
+        from: usize,
+    ) -> Option<(usize, usize)> {
+        let mut search = Search::new(&pattern.masks, pattern.len, self.edits, false);
+        let mut best: Option<(usize, usize)> = None;
+        for (i, &(_, c)) in chars.iter().enumerate().skip(from) {
+            match (search.read(c), best) {
+                (Some(edits), Some((_, fewest))) if edits > fewest => break,
+                (Some(edits), _) => best = Some((i + 1, edits)),
+                (None, Some(_)) => break,
+                (None, None) => (),
+            }
+        }
+        let (end, edits) = best?;
+
+        let mut search = Search::new(&pattern.reversed_masks, pattern.len, self.edits, true);
+        let mut start = end;
+        for i in (from..end).rev().take(pattern.len + edits) {
+            if search
+                .read(chars[i].1)
+                .is_some_and(|fewest| fewest <= edits)
+            {
+                start = i;
+            }
+        }
+        Some((start, end))
+    }
+
+    /// Returns every match of the patterns in `text`, from left to right and without
+    /// overlapping, as byte ranges of `text` paired with the index of the pattern they
+    /// come from. Where matches of several patterns overlap, the one starting first is
+    /// kept, or the one of the pattern given first.
+    pub(crate) fn find_iter(&self, text: &str) -> Vec<(usize, usize, usize)> {
+        let chars: Vec<(usize, char)> = text
+            .char_indices()
+            .map(|(offset, c)| (offset, fold_case(c, self.ignore_case)))
+            .collect();
+        let offset = |i: usize| chars.get(i).map_or(text.len(), |&(offset, _)| offset);
+        let mut found: Vec<(usize, usize, usize)> = Vec::new();
+        // The next match of each pattern, found from an earlier position
+        let mut next: Vec<Option<(usize, usize)>> = self
+            .patterns
+            .iter()
+            .map(|pattern| self.find_at(pattern, &chars, 0))
+            .collect();
+        let mut from = 0;
+        loop {
+            for (pattern, next) in self.patterns.iter().zip(next.iter_mut()) {
+                if next.is_some_and(|(start, _)| start < from) {
+                    *next = self.find_at(pattern, &chars, from);
+                }
+            }
+            let first = (0..next.len())
+                .filter_map(|i| next[i].map(|(start, end)| (start, end, i)))
+                .min_by_key(|&(start, _, i)| (start, i));
+            match first {
+                Some((start, end, i)) => {
+                    found.push((offset(start), offset(end), i));
+                    from = end;
+                }
+                None => return found,
+            }
+        }
+    }
+}
+
+/// Folds the case of `c` with `--ignore-case`.
+fn fold_case(c: char, ignore_case: bool) -> char {
+    match ignore_case {
+        true => c.to_lowercase().next().unwrap_or(c),
+        false => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find<'t>(pattern: &str, edits: usize, text: &'t str) -> Vec<&'t str> {
+        let matcher = FuzzyMatcher::new(&[pattern.to_string()], edits, false).unwrap();
+        matcher
+            .find_iter(text)
+            .into_iter()
+            .map(|(start, end, _)| &text[start..end])
+            .collect()
+    }
+
+    #[test]
+    fn matches_within_edits() {
+        // Exact, substitution, deletion and insertion
+        for text in ["farewell", "farewall", "farwell", "fare-well"] {
+            assert_eq!(find("farewell", 1, text), vec![text]);
+        }
+        assert!(find("farewell", 1, "fair well").is_empty());
+        // The match with the fewest edits is preferred over longer ones
+        assert_eq!(find("farewell", 1, "a farewells"), vec!["farewell"]);
+        // Characters are taken literally
+        assert_eq!(find("a.c", 1, "x.c xbc"), vec!["x.c"]);
+        assert_eq!(
+            find("farewell", 2, "fairwell, farewel"),
+            vec!["fairwell", "farewel"]
+        );
+
+        // Phrases take as many edits as asked for
+        let text = "and I’d gladly scream of the unritten gospal of forgeting";
+        assert_eq!(
+            find("the unwritten gospel", 3, text),
+            vec!["the unritten gospal"]
+        );
+        let pattern = "the unwritten gospel of forgetting".repeat(3);
+        let text = "the unritten gospal of forgeting".repeat(3);
+        assert_eq!(find(&pattern, 12, &text), vec![text.as_str()]);
+
+        let matcher =
+            FuzzyMatcher::new(&["soul".to_string(), "SPIRIT".to_string()], 1, true).unwrap();
+        assert_eq!(
+            matcher.find_iter("Spirt and soal"),
+            vec![(0, 5, 1), (10, 14, 0)]
+        );
+        assert!(matcher.is_match("a sou"));
+        assert!(!matcher.is_match("a so"));
+        assert!(matches!(
+            FuzzyMatcher::new(&["abc".to_string()], 3, false),
+            Err(CliError::FuzzyEdits { edits: 3, .. })
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+use regex::{Captures, Regex};
+
+use crate::core::fuzzy::FuzzyMatcher;
+use crate::core::utils::pattern_index;
+
+/// What the patterns are searched with: a regular expression, as compiled by
+/// `compile_patterns`, or the approximate matcher of `--fuzzy`.
+#[derive(Debug, Clone)]
+pub(crate) enum Matcher {
+    Regex(Regex),
+    Fuzzy(FuzzyMatcher),
+}
+
+/// How a `Match` was found.
+enum Found<'m, 't> {
+    /// By the regex, with these capture groups
+    Regex(&'m Regex, Captures<'t>),
+    /// By the fuzzy matcher, for the pattern at this index
+    Fuzzy(usize),
+}
+
+/// A match found by a `Matcher` in the text `text`.
+pub(crate) struct Match<'m, 't> {
+    /// Byte offset the match starts at within `text`
+    pub(crate) start: usize,
+    /// Byte offset right after the match within `text`
+    pub(crate) end: usize,
+    text: &'t str,
+    found: Found<'m, 't>,
+}
+
+impl From<Regex> for Matcher {
+    fn from(re: Regex) -> Self {
+        Matcher::Regex(re)
+    }
+}
+
+impl Matcher {
+    /// Returns whether there's a match somewhere in `text`.
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Regex(re) => re.is_match(text),
+            Matcher::Fuzzy(fuzzy) => fuzzy.is_match(text),
+        }
+    }
+
+    /// Returns every match in `text`, from left to right and without overlapping.
+    pub(crate) fn find_iter<'m, 't>(&'m self, text: &'t str) -> Vec<Match<'m, 't>> {
+        match self {
+            Matcher::Regex(re) => re
+                .captures_iter(text)
+                .map(|caps| {
+                    let whole = caps.get(0).unwrap();
+                    Match {
+                        start: whole.start(),
+                        end: whole.end(),
+                        text,
+                        found: Found::Regex(re, caps),
+                    }
+                })
+                .collect(),
+            Matcher::Fuzzy(fuzzy) => fuzzy
+                .find_iter(text)
+                .into_iter()
+                .map(|(start, end, pattern)| Match {
+                    start,
+                    end,
+                    text,
+                    found: Found::Fuzzy(pattern),
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns `text` with every match replaced by what `replacement` returns for it.
+    pub(crate) fn replace_all(
+        &self,
+        text: &str,
+        mut replacement: impl FnMut(&Match) -> String,
+    ) -> String {
+        let mut replaced = String::with_capacity(text.len());
+        let mut last = 0;
+        for mat in self.find_iter(text) {
+            replaced.push_str(&text[last..mat.start]);
+            replaced.push_str(&replacement(&mat));
+            last = mat.end;
+        }
+        replaced.push_str(&text[last..]);
+        replaced
+    }
+}
+
+impl Match<'_, '_> {
+    /// Returns the matched text.
+    pub(crate) fn as_str(&self) -> &str {
+        &self.text[self.start..self.end]
+    }
+
+    /// Returns the index of the pattern given to `compile_patterns` that the match
+    /// comes from. Patterns are numbered in the order they're given, starting from 0.
+    pub(crate) fn pattern(&self) -> usize {
+        match &self.found {
+            Found::Regex(re, caps) => re
+                .capture_names()
+                .enumerate()
+                .find(|&(i, name)| pattern_index(name).is_some() && caps.get(i).is_some())
+                .and_then(|(_, name)| pattern_index(name))
+                .unwrap_or(0),
+            Found::Fuzzy(pattern) => *pattern,
+        }
+    }
+
+    /// Returns the number of the color of the pattern that the match comes from,
+    /// as described by `group_color`.
+    pub(crate) fn color(&self) -> usize {
+        match &self.found {
+            Found::Regex(re, caps) => re
+                .capture_names()
+                .enumerate()
+                .find(|&(i, name)| pattern_index(name).is_some() && caps.get(i).is_some())
+                .map_or(0, |(i, _)| group_color(re, i)),
+            Found::Fuzzy(pattern) => *pattern,
+        }
+    }
+
+    /// Splits the match into the segments highlighted in different colors, as
+    /// `(start, end, color)` byte ranges of the searched text.
+    ///
+    /// The whole match takes the color of its pattern, as given by `color`, and
+    /// each capture group within it its own color, as given by `group_color`. A group
+    /// nested in another one shows over it.
+    pub(crate) fn segments(&self) -> Vec<(usize, usize, usize)> {
+        let mut colors = vec![self.color(); self.end - self.start];
+        if let Found::Regex(re, caps) = &self.found {
+            // Only the groups of the pattern the match comes from take part in it
+            for (i, name) in re.capture_names().enumerate().skip(1) {
+                if let (None, Some(group)) = (pattern_index(name), caps.get(i)) {
+                    let color = group_color(re, i);
+                    colors[group.start() - self.start..group.end() - self.start]
+                        .iter_mut()
+                        .for_each(|c| *c = color);
+                }
+            }
+        }
+
+        let mut segments: Vec<(usize, usize, usize)> = Vec::new();
+        for (offset, color) in colors.into_iter().enumerate() {
+            let position = self.start + offset;
+            match segments.last_mut() {
+                Some((_, end, last_color)) if *last_color == color => *end = position + 1,
+                _ => segments.push((position, position + 1, color)),
+            }
+        }
+        segments
+    }
+
+    /// Returns the `--replace` template with `$0`, `$1` or `${name}` replaced by the
+    /// text of the respective capture group of the match. Fuzzy matches only have
+    /// the whole match, so any other group stands for nothing.
+    pub(crate) fn expand(&self, template: &str) -> String {
+        let mut expanded = String::new();
+        match &self.found {
+            Found::Regex(_, caps) => caps.expand(template, &mut expanded),
+            Found::Fuzzy(_) => {
+                let mut rest = template;
+                while let Some(dollar) = rest.find('$') {
+                    expanded.push_str(&rest[..dollar]);
+                    rest = &rest[dollar + 1..];
+                    if let Some(after) = rest.strip_prefix('$') {
+                        expanded.push('$');
+                        rest = after;
+                        continue;
+                    }
+                    let (name, after) = match rest.strip_prefix('{').and_then(|r| r.split_once('}'))
+                    {
+                        Some(braced) => braced,
+                        None => {
+                            let len = rest
+                                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                                .unwrap_or(rest.len());
+                            rest.split_at(len)
+                        }
+                    };
+                    match name {
+                        "" => expanded.push('$'),
+                        "0" => expanded.push_str(self.as_str()),
+                        _ => (),
+                    }
+                    if !name.is_empty() {
+                        rest = after;
+                    }
+                }
+                expanded.push_str(rest);
+            }
+        }
+        expanded
+    }
+}
+
+/// Returns the number of the color of the capture group at index `group` of `re`.
+///
+/// Every pattern given to `compile_patterns` and every capture group written in one
+/// gets a color of its own, numbered in the order they're written in, starting from 0.
+/// Since the patterns are wrapped in capture groups themselves, that's the index of the
+/// group, less the implicit group of the whole match. A single pattern isn't wrapped,
+/// so its whole match takes the color 0 and its capture groups the next ones.
+fn group_color(re: &Regex, group: usize) -> usize {
+    match re.capture_names().any(|name| pattern_index(name).is_some()) {
+        true => group.saturating_sub(1),
+        false => group,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::flag::Flags;
+    use crate::core::utils::compile_patterns;
+
+    #[test]
+    fn fuzzy_matches_expand_the_whole_match() {
+        let flags = Flags {
+            fuzzy: Some(1),
+            ..Flags::default()
+        };
+        let matcher = compile_patterns(&["farewell"], &flags).unwrap();
+        assert_eq!(
+            matcher.replace_all("a farwell letter", |mat| mat.expand("[$0|${0}|$1|$$|$]")),
+            "a [farwell|farwell||$|$] letter"
+        );
+    }
+}
@@ -1,5 +1,12 @@
 pub mod cli;
+pub mod color;
 pub mod error;
 pub mod flag;
+pub mod fuzzy;
+pub mod matcher;
+pub mod normalize;
+pub mod posix;
 pub mod process;
+pub mod query;
+pub mod rewrite;
 pub mod utils;
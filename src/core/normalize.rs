@@ -0,0 +1,332 @@
+use regex_syntax::ast::{self, Ast};
+use std::borrow::Cow;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::core::flag::Flags;
+
+/// The Unicode normalization form text is folded to with `--normalize`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Canonical composition, under which `e` followed by a combining acute accent is `é`
+    Nfc,
+    /// Compatibility composition, which also folds ligatures like `ﬁ` into `fi` and
+    /// full-width letters into ASCII ones. Typographic quotes like `’` are folded into
+    /// ASCII ones as well
+    Nfkc,
+}
+
+/// Text folded by `--normalize` or `--ignore-accents`, or a pattern rewritten into the
+/// syntax of the `regex` crate, which keeps track of where each part of it comes from
+/// in the original text.
+pub(crate) struct Folded<'t> {
+    pub(crate) original: &'t str,
+    pub(crate) text: Cow<'t, str>,
+    /// The byte offsets that each piece of the folded text starts at, paired with the
+    /// ones that the piece of the original text it comes from starts at. `None` when
+    /// the text isn't folded at all.
+    starts: Option<Vec<(usize, usize)>>,
+}
+
+impl<'t> Folded<'t> {
+    /// Returns `text` as it is.
+    pub(crate) fn unchanged(text: &'t str) -> Self {
+        Folded {
+            original: text,
+            text: Cow::Borrowed(text),
+            starts: None,
+        }
+    }
+
+    /// Returns `text`, rewritten from `original` piece by piece, where `starts` pairs the
+    /// byte offsets that each piece starts at in `text` with the ones it starts at in `original`.
+    pub(crate) fn rewritten(original: &'t str, text: String, starts: Vec<(usize, usize)>) -> Self {
+        Folded {
+            original,
+            text: Cow::Owned(text),
+            starts: Some(starts),
+        }
+    }
+
+    /// Maps the byte range `start..end` of the folded text back to the original text.
+    /// A range starting or ending in the middle of the folding of a character, like
+    /// within the `fi` of `ﬁ`, is widened to the whole character.
+    pub(crate) fn original_span(&self, (start, end): (usize, usize)) -> (usize, usize) {
+        let starts = match &self.starts {
+            Some(starts) => starts,
+            None => return (start, end),
+        };
+        let piece_of = |offset: usize| {
+            starts
+                .partition_point(|&(folded, _)| folded <= offset)
+                .saturating_sub(1)
+        };
+        let original_start = starts
+            .get(piece_of(start))
+            .map_or(0, |&(_, original)| original);
+        if end <= start {
+            return (original_start, original_start);
+        }
+        let original_end = starts
+            .get(piece_of(end - 1) + 1)
+            .map_or(self.original.len(), |&(_, original)| original);
+        (original_start, original_end)
+    }
+
+    /// Maps the `(start, end, color)` segments of the folded text, as returned by
+    /// `Match::segments`, back to the original text. Segments widened by `original_span`
+    /// are cut short where the previous one ends, so that they don't overlap.
+    pub(crate) fn original_segments(
+        &self,
+        segments: &[(usize, usize, usize)],
+    ) -> Vec<(usize, usize, usize)> {
+        let mut mapped: Vec<(usize, usize, usize)> = Vec::with_capacity(segments.len());
+        for &(start, end, color) in segments {
+            let (start, end) = self.original_span((start, end));
+            let start = mapped
+                .last()
+                .map_or(start, |&(_, last_end, _)| start.max(last_end));
+            if start < end {
+                mapped.push((start, end, color));
+            }
+        }
+        mapped
+    }
+}
+
+/// Folds a typographic quote into the ASCII one it stands for.
+fn fold_quote(c: char) -> char {
+    match c {
+        '‘' | '’' | '‚' | '‛' | '′' => '\'',
+        '“' | '”' | '„' | '‟' | '″' => '"',
+        c => c,
+    }
+}
+
+/// Folds `piece`, a character along with the combining marks following it, as described by `fold`.
+fn fold_piece(piece: &str, flags: &Flags) -> String {
+    let compatibility = flags.normalize == Some(Normalization::Nfkc);
+    let folded: String = match (compatibility, flags.ignore_accents) {
+        (true, true) => piece.nfkd().filter(|&c| !is_combining_mark(c)).collect(),
+        (false, true) => piece.nfd().filter(|&c| !is_combining_mark(c)).collect(),
+        (true, false) => piece.nfkc().collect(),
+        (false, false) => piece.nfc().collect(),
+    };
+    match compatibility {
+        true => folded.chars().map(fold_quote).collect(),
+        false => folded,
+    }
+}
+
+/// Folds `text` to the Unicode normalization form given with `--normalize`, and strips
+/// the accents and other combining marks off its characters with `--ignore-accents`, so
+/// that the patterns and the input can be compared in the same form. Without either
+/// option, the text is left as it is.
+///
+/// Each character is folded along with the combining marks following it, so that every
+/// part of the folded text can be traced back to the original one.
+pub(crate) fn fold<'t>(text: &'t str, flags: &Flags) -> Folded<'t> {
+    if flags.normalize.is_none() && !flags.ignore_accents {
+        return Folded::unchanged(text);
+    }
+    let mut folded = String::with_capacity(text.len());
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    let mut piece_start = 0;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        if i == text.len() || (i > 0 && !is_combining_mark(c)) {
+            starts.push((folded.len(), piece_start));
+            folded.push_str(&fold_piece(&text[piece_start..i], flags));
+            piece_start = i;
+        }
+    }
+    Folded {
+        original: text,
+        text: Cow::Owned(folded),
+        starts: Some(starts),
+    }
+}
+
+/// How a literal character of a pattern is used, which decides how it can be folded.
+#[derive(Clone, Copy, PartialEq)]
+enum LiteralUse {
+    /// Matched in sequence with the characters around it, so that combining marks
+    /// are folded along with the character before them
+    Sequence,
+    /// Repeated on its own, like the `…` of `…+`, so that it's grouped if it folds
+    /// into several characters
+    Repeated,
+    /// An item of a character class, like the `é` of `[éè]`, which is only folded
+    /// if it folds into a single character
+    ClassItem,
+}
+
+/// Collects the literal characters of a pattern, along with their spans and how they're used.
+#[derive(Default)]
+struct Literals {
+    literals: Vec<(ast::Span, char, LiteralUse)>,
+    /// The offsets of the literals that are the operand of a repetition
+    repeated: Vec<usize>,
+}
+
+impl ast::Visitor for Literals {
+    type Output = Vec<(ast::Span, char, LiteralUse)>;
+    type Err = ();
+
+    fn finish(self) -> Result<Self::Output, ()> {
+        Ok(self.literals)
+    }
+
+    fn visit_pre(&mut self, ast: &Ast) -> Result<(), ()> {
+        match ast {
+            Ast::Repetition(repetition) => {
+                if let Ast::Literal(literal) = &*repetition.ast {
+                    self.repeated.push(literal.span.start.offset);
+                }
+            }
+            Ast::Literal(literal) => {
+                let usage = match self.repeated.contains(&literal.span.start.offset) {
+                    true => LiteralUse::Repeated,
+                    false => LiteralUse::Sequence,
+                };
+                self.literals.push((literal.span, literal.c, usage));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn visit_class_set_item_pre(&mut self, item: &ast::ClassSetItem) -> Result<(), ()> {
+        if let ast::ClassSetItem::Literal(literal) = item {
+            self.literals
+                .push((literal.span, literal.c, LiteralUse::ClassItem));
+        }
+        Ok(())
+    }
+}
+
+/// Folds the literal characters of the regular expression `pattern` like `fold` folds
+/// text, and escapes what they fold into, so that folding never turns them into syntax,
+/// like `…` into `...` or a full-width `（` into `(`. A literal character repeated on
+/// its own that folds into several ones is grouped, and one in a character class is
+/// only folded if it folds into a single character.
+///
+/// A pattern that doesn't parse is left as it is, for its syntax error to be reported.
+pub(crate) fn fold_pattern<'p>(pattern: &'p str, flags: &Flags) -> Folded<'p> {
+    if flags.normalize.is_none() && !flags.ignore_accents {
+        return Folded::unchanged(pattern);
+    }
+    let literals = match ast::parse::Parser::new().parse(pattern) {
+        Ok(ast) => ast::visit(&ast, Literals::default()).unwrap_or_default(),
+        Err(_) => return Folded::unchanged(pattern),
+    };
+
+    let mut folded = String::with_capacity(pattern.len());
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    // Byte offset in `pattern` up to which it's been copied or folded into `folded`
+    let mut copied = 0;
+    let mut i = 0;
+    while i < literals.len() {
+        let (span, c, usage) = literals[i];
+        // A character is folded along with the combining marks matched right after it
+        let mut end = span.end.offset;
+        let mut piece = c.to_string();
+        i += 1;
+        while let Some(&(next, c, LiteralUse::Sequence)) = literals.get(i) {
+            if usage != LiteralUse::Sequence || next.start.offset != end || !is_combining_mark(c) {
+                break;
+            }
+            piece.push(c);
+            end = next.end.offset;
+            i += 1;
+        }
+        let piece_folded = fold_piece(&piece, flags);
+        let replacement = match (usage, piece_folded.chars().count()) {
+            _ if piece_folded == piece => continue,
+            (LiteralUse::ClassItem, len) if len != 1 => continue,
+            (LiteralUse::Repeated, len) if len != 1 => {
+                format!("(?:{})", regex::escape(&piece_folded))
+            }
+            _ => regex::escape(&piece_folded),
+        };
+        for (offset, _) in pattern[copied..span.start.offset].char_indices() {
+            starts.push((folded.len() + offset, copied + offset));
+        }
+        folded.push_str(&pattern[copied..span.start.offset]);
+        starts.push((folded.len(), span.start.offset));
+        folded.push_str(&replacement);
+        copied = end;
+    }
+    if copied == 0 {
+        return Folded::unchanged(pattern);
+    }
+    for (offset, _) in pattern[copied..].char_indices() {
+        starts.push((folded.len() + offset, copied + offset));
+    }
+    folded.push_str(&pattern[copied..]);
+    Folded::rewritten(pattern, folded, starts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::utils::compile_patterns;
+
+    #[test]
+    fn folded_spans_map_back_to_the_original() {
+        let flags = Flags {
+            normalize: Some(Normalization::Nfkc),
+            ignore_accents: true,
+            ..Flags::default()
+        };
+        // `é` written as `e` followed by a combining acute accent
+        let text = "I’m at the cafe\u{301}, ﬁnally";
+        let folded = fold(text, &flags);
+        assert_eq!(folded.text, "I'm at the cafe, finally");
+        assert_eq!(folded.original_span((0, 3)), (0, 5));
+        assert_eq!(folded.original_span((11, 15)), (13, 19));
+        // Within the `fi` ligature
+        assert_eq!(folded.original_span((18, 20)), (21, 25));
+        assert_eq!(
+            folded.original_segments(&[(17, 18, 0), (18, 19, 1), (19, 24, 0)]),
+            vec![(21, 24, 0), (24, 29, 0)]
+        );
+
+        let flags = Flags {
+            normalize: Some(Normalization::Nfc),
+            ..Flags::default()
+        };
+        let folded = fold("cafe\u{301} I’m", &flags);
+        assert_eq!(folded.text, "café I’m");
+        assert_eq!(folded.original_span((3, 5)), (3, 6));
+        assert_eq!(fold("", &flags).original_span((0, 0)), (0, 0));
+    }
+
+    #[test]
+    fn only_literals_of_patterns_are_folded() {
+        let flags = Flags {
+            normalize: Some(Normalization::Nfkc),
+            ignore_accents: true,
+            ..Flags::default()
+        };
+        // An ellipsis and a full-width parenthesis stay literal
+        let folded = fold_pattern(r"wait…（\d+）", &flags);
+        assert_eq!(folded.text, r"wait\.\.\.\(\d+\)");
+        assert_eq!(folded.original_span((4, 10)), (4, 7));
+        assert_eq!(folded.original_span((12, 15)), (10, 13));
+        assert_eq!(
+            fold_pattern("…+ca\\x{301}fe\u{301}?", &flags).text,
+            r"(?:\.\.\.)+cafe(?:)?"
+        );
+        assert_eq!(fold_pattern("[ﬁé…-]", &flags).text, "[ﬁe…-]");
+        // Left as it is when it doesn't parse
+        assert_eq!(fold_pattern("é(", &flags).text, "é(");
+
+        let matcher = compile_patterns(&["wait…", "a（b"], &flags).unwrap();
+        assert!(!matcher.is_match("waiting"));
+        assert!(matcher.is_match("wait..."));
+        assert!(matcher.is_match("a(b"));
+    }
+}
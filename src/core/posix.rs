@@ -0,0 +1,303 @@
+use crate::core::error::{PatternError, BACKREFERENCE_HINT};
+use crate::core::normalize::Folded;
+
+/// The POSIX regular expression syntaxes that patterns can be written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PosixSyntax {
+    /// Basic regular expressions, as with `grep -G`, where `\(`, `\)`, `\{`, `\}`, `\|`,
+    /// `\+` and `\?` are the metacharacters and the characters themselves are literal
+    Basic,
+    /// Extended regular expressions, as with `grep -E`
+    Extended,
+}
+
+/// Returns an error about the characters of `pattern` in the range `columns`, explained
+/// by `message`, with advice on the usual mistake behind it.
+fn syntax_error(pattern: &str, columns: (usize, usize), message: &str, hint: &str) -> PatternError {
+    PatternError::at(pattern, message.to_string(), columns, Some(hint))
+}
+
+/// Translates the contents of an interval expression, like `2,5` or `,3`, into the
+/// repetition it stands for. Returns `None` if it isn't a valid interval.
+fn interval(contents: &str) -> Option<String> {
+    let (min, max) = match contents.split_once(',') {
+        Some((min, max)) => (min, Some(max)),
+        None => (contents, None),
+    };
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if !(is_number(min) || (min.is_empty() && max.is_some_and(is_number))) {
+        return None;
+    }
+    if max.is_some_and(|max| !max.is_empty() && !is_number(max)) {
+        return None;
+    }
+    let min = if min.is_empty() { "0" } else { min };
+    Some(match max {
+        Some(max) => format!("{{{},{}}}", min, max),
+        None => format!("{{{}}}", min),
+    })
+}
+
+/// Translates the bracket expression starting at `chars[start]`, like `[^]a-z[:digit:]]`,
+/// and appends it to `translated`. Returns the index right after it.
+///
+/// Within a bracket expression, a `]` right after the opening `[` or `[^` is literal,
+/// and so is a backslash. Character classes like `[:alpha:]` are kept as they are, while
+/// equivalence classes like `[=a=]` and collating symbols like `[.-.]` stand for their
+/// character.
+fn bracket(
+    chars: &[char],
+    start: usize,
+    pattern: &str,
+    translated: &mut String,
+) -> Result<usize, PatternError> {
+    let mut i = start + 1;
+    translated.push('[');
+    if chars.get(i) == Some(&'^') {
+        translated.push('^');
+        i += 1;
+    }
+    if chars.get(i) == Some(&']') {
+        translated.push_str("\\]");
+        i += 1;
+    }
+    loop {
+        match (chars.get(i), chars.get(i + 1)) {
+            (None, _) => {
+                let hint = "to match a literal [, escape it as \\[";
+                return Err(syntax_error(
+                    pattern,
+                    (start, start + 1),
+                    "unmatched [",
+                    hint,
+                ));
+            }
+            (Some(']'), _) => {
+                translated.push(']');
+                return Ok(i + 1);
+            }
+            (Some('['), Some(&delimiter)) if matches!(delimiter, ':' | '=' | '.') => {
+                let contents_start = i + 2;
+                let end = (contents_start..chars.len().saturating_sub(1))
+                    .find(|&j| chars[j] == delimiter && chars[j + 1] == ']')
+                    .ok_or_else(|| {
+                        let message = format!("unmatched [{}", delimiter);
+                        let hint = format!("close it with {}]", delimiter);
+                        syntax_error(pattern, (i, i + 2), &message, &hint)
+                    })?;
+                let contents: String = chars[contents_start..end].iter().collect();
+                match delimiter {
+                    ':' => translated.push_str(&format!("[:{}:]", contents)),
+                    _ => translated.push_str(&regex::escape(&contents)),
+                }
+                i = end + 2;
+            }
+            (Some(&c), _) => {
+                if matches!(c, '\\' | '[' | '&' | '~') {
+                    translated.push('\\');
+                }
+                translated.push(c);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Translates `pattern`, written in the POSIX `syntax` like GNU grep reads it, into the
+/// syntax of the `regex` crate. The GNU extensions `\w`, `\W`, `\s`, `\S`, `\b` and `\B`
+/// are kept, while `\<` and `\>` become word boundaries. Back-references aren't supported.
+///
+/// In basic regular expressions, `*` at the start of the pattern or of a group is literal,
+/// `^` is only an anchor at the start of either and `$` only at the end of either.
+/// In both syntaxes, an interval like `{,3}` means `{0,3}`.
+///
+/// Each metacharacter, escape, bracket expression or interval is translated as a piece,
+/// so that a syntax error in the translation can be traced back to the pattern.
+pub(crate) fn translate(pattern: &str, syntax: PosixSyntax) -> Result<Folded<'_>, PatternError> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let offsets: Vec<usize> = pattern.char_indices().map(|(offset, _)| offset).collect();
+    let basic = syntax == PosixSyntax::Basic;
+    let mut translated = String::with_capacity(pattern.len());
+    let mut starts: Vec<(usize, usize)> = Vec::with_capacity(chars.len());
+    // Whether the next character starts the pattern, a group or an alternative
+    let mut at_start = true;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let starts_group = at_start;
+        at_start = false;
+        starts.push((translated.len(), offsets[i]));
+        match c {
+            '[' => {
+                i = bracket(&chars, i, pattern, &mut translated)?;
+                continue;
+            }
+            '\\' => {
+                let escaped = *chars.get(i + 1).ok_or_else(|| {
+                    let hint = "to match a literal backslash, escape it as \\\\";
+                    syntax_error(pattern, (i, i + 1), "trailing backslash", hint)
+                })?;
+                i += 2;
+                match escaped {
+                    '(' | '|' if basic => {
+                        translated.push(escaped);
+                        at_start = true;
+                    }
+                    ')' | '+' | '?' if basic => translated.push(escaped),
+                    '{' if basic => {
+                        let end = (i..chars.len().saturating_sub(1))
+                            .find(|&j| chars[j] == '\\' && chars[j + 1] == '}')
+                            .ok_or_else(|| {
+                                let hint = "to match a literal {, write it without a backslash";
+                                syntax_error(pattern, (i - 2, i), "unmatched \\{", hint)
+                            })?;
+                        let contents: String = chars[i..end].iter().collect();
+                        let repetition = interval(&contents).ok_or_else(|| {
+                            let hint = "intervals are written like \\{2\\}, \\{2,5\\}, \\{2,\\} or \\{,5\\}";
+                            syntax_error(pattern, (i - 2, end + 2), "invalid content of \\{\\}", hint)
+                        })?;
+                        translated.push_str(&repetition);
+                        i = end + 2;
+                    }
+                    '<' | '>' => translated.push_str("\\b"),
+                    'w' | 'W' | 's' | 'S' | 'b' | 'B' => {
+                        translated.push('\\');
+                        translated.push(escaped);
+                    }
+                    '1'..='9' => {
+                        let message = "back-references aren't supported";
+                        return Err(syntax_error(
+                            pattern,
+                            (i - 2, i),
+                            message,
+                            BACKREFERENCE_HINT,
+                        ));
+                    }
+                    _ => translated.push_str(&regex::escape(&escaped.to_string())),
+                }
+                continue;
+            }
+            '*' if starts_group => translated.push_str("\\*"),
+            '^' if basic && !starts_group => translated.push_str("\\^"),
+            '^' => {
+                translated.push('^');
+                at_start = basic;
+            }
+            '$' if basic => {
+                let rest: String = chars[i + 1..].iter().take(2).collect();
+                match rest.is_empty() || rest == "\\)" || rest == "\\|" {
+                    true => translated.push('$'),
+                    false => translated.push_str("\\$"),
+                }
+            }
+            '(' | ')' | '|' | '+' | '?' | '{' | '}' if basic => {
+                translated.push('\\');
+                translated.push(c);
+            }
+            '(' | '|' => {
+                translated.push(c);
+                at_start = true;
+            }
+            '{' => {
+                // A brace that doesn't open a valid interval is literal, like in GNU grep
+                let end = chars[i..].iter().position(|&c| c == '}').map(|end| i + end);
+                let repetition =
+                    end.and_then(|end| interval(&chars[i + 1..end].iter().collect::<String>()));
+                match (end, repetition) {
+                    (Some(end), Some(repetition)) => {
+                        translated.push_str(&repetition);
+                        i = end + 1;
+                        continue;
+                    }
+                    _ => translated.push_str("\\{"),
+                }
+            }
+            '}' | ']' => {
+                translated.push('\\');
+                translated.push(c);
+            }
+            _ => translated.push(c),
+        }
+        i += 1;
+    }
+    Ok(Folded::rewritten(pattern, translated, starts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic_regular_expressions() {
+        let translate = |pattern: &str| {
+            translate(pattern, PosixSyntax::Basic)
+                .unwrap()
+                .text
+                .into_owned()
+        };
+        assert_eq!(translate(r"\(ab\)\{2,\}"), "(ab){2,}");
+        assert_eq!(translate(r"a\|b+(c)?{d}"), r"a|b\+\(c\)\?\{d\}");
+        assert_eq!(translate(r"*a^b$c\(^d$\)$"), r"\*a\^b\$c(^d$)$");
+        assert_eq!(translate(r"x\{,3\}\.\<w\>"), r"x{0,3}\.\bw\b");
+        assert_eq!(
+            translate(r"[]a\[x-][[:alpha:]][^]]"),
+            r"[\]a\\\[x-][[:alpha:]][^\]]"
+        );
+        assert!(super::translate(r"\(a\)\1", PosixSyntax::Basic).is_err());
+        assert!(super::translate(r"a\{x\}", PosixSyntax::Basic).is_err());
+        assert!(super::translate(r"[[:alpha:]", PosixSyntax::Basic).is_err());
+    }
+
+    #[test]
+    fn extended_regular_expressions() {
+        let translate = |pattern: &str| {
+            translate(pattern, PosixSyntax::Extended)
+                .unwrap()
+                .text
+                .into_owned()
+        };
+        assert_eq!(translate(r"(ab|*c)+{2}$"), r"(ab|\*c)+{2}$");
+        assert_eq!(translate(r"a{,3}b{x}c}"), r"a{0,3}b\{x\}c\}");
+        assert_eq!(translate(r"\(\)[[=e=][.-.]]"), r"\(\)[e\-]");
+        assert!(super::translate(r"a\", PosixSyntax::Extended).is_err());
+    }
+
+    #[test]
+    fn translation_errors_point_at_the_mistake() {
+        // The error message along with the caret under the mistake
+        let error = |pattern: &str| {
+            let err = translate(pattern, PosixSyntax::Basic)
+                .err()
+                .unwrap()
+                .to_string();
+            let lines: Vec<&str> = err.lines().collect();
+            format!("{}\n{}", lines[0], lines[2])
+        };
+        assert_eq!(error(r"é\{2"), "invalid pattern: unmatched \\{\n     ^^");
+        assert_eq!(
+            error(r"a\{,x\}"),
+            "invalid pattern: invalid content of \\{\\}\n     ^^^^^^"
+        );
+        assert_eq!(
+            error(r"ab[[=e="),
+            "invalid pattern: unmatched [=\n       ^^"
+        );
+        assert_eq!(
+            error(r"\(a\)\1"),
+            "invalid pattern: back-references aren't supported\n         ^^"
+        );
+        assert_eq!(
+            error(r"ab\"),
+            "invalid pattern: trailing backslash\n      ^"
+        );
+    }
+
+    #[test]
+    fn translations_map_back_to_the_pattern() {
+        let translated = translate(r"é\{2\}\(a", PosixSyntax::Basic).unwrap();
+        assert_eq!(translated.text, "é{2}(a");
+        // `{2}` comes from `\{2\}` and `(` from `\(`
+        assert_eq!(translated.original_span((2, 5)), (2, 7));
+        assert_eq!(translated.original_span((5, 6)), (7, 9));
+    }
+}
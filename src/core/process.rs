@@ -1,432 +1,1115 @@
 use regex::Regex;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Write};
 use std::path::Path;
+use std::time::{Duration, Instant};
 
-use crate::getwriter;
+use crate::core::color::{hyperlink, Colors};
 use crate::core::error::CliError;
 use crate::core::flag::Flags;
-use crate::core::utils::{compile_regex, parse_context_number, Colors, ContextKind};
+use crate::core::matcher::{Match, Matcher};
+use crate::core::normalize::{fold, Folded};
+use crate::core::rewrite::rewrite_file;
+use crate::core::utils::{
+    compile_patterns, compile_regex, json_string, lines_with_byte_offsets, parse_context_number,
+    ContextKind, CountingReader,
+};
+use crate::getwriter;
 
-/// Calculates the number of matches found
-/// according to the regex pattern and returns it.
-fn count_matches<T: BufRead + Sized>(reader: T, re: Regex) -> u32 {
-    let mut matches: u32 = 0;
-    reader.lines().for_each(|line| {
-        re.find(&line.unwrap()).is_some().then(|| matches += 1);
-    });
-    matches
+/// Percent-encodes `path` for use in a URL, leaving the `/` separators as they are.
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
 }
 
-/// Prints trailing context lines with or without line numbers.
-/// Each group of match and its context is separated by `group_separator`.
-fn print_with_after_context<T: BufRead + Sized>(
-    reader: T,
-    re: Regex,
+/// Makes `text` a hyperlink to the given line and column of `file_name`, following
+/// the template of `--hyperlink-format`. Without a template, or when reading the standard
+/// input, `text` is returned as it is. A missing column stands for the start of the line.
+fn link_to_file(
+    text: String,
+    file_name: &str,
+    line_number: usize,
+    column: Option<usize>,
     flags: &Flags,
-    context_number: usize,
-    group_separator: &str,
-    mut writer: impl Write,
-) -> Result<(), CliError> {
-    // We need to iterate over the `reader` content twice, which is not possible so
-    // we move them to a Vector that we can iterate over more than once.
-    let lines = reader.lines().collect::<std::io::Result<Vec<String>>>()?;
-    // For line numbers where matches occur
-    let mut matched_line_numbers: Vec<usize> = Vec::with_capacity(lines.len());
-    // Stores each matching line and line number as a tuple Vector
-    let mut matched_lines_with_number: Vec<Vec<(usize, String)>> = Vec::with_capacity(lines.len());
-
-    for (i, line_) in lines.iter().enumerate() {
-        if re.find(line_).is_some() {
-            matched_line_numbers.push(i);
-            let v = Vec::with_capacity(context_number + 1);
-            matched_lines_with_number.push(v);
-        } else {
+) -> String {
+    let template = match &flags.hyperlink_format {
+        Some(template) => template,
+        None => return text,
+    };
+    let path = match std::path::absolute(file_name) {
+        Ok(path) if file_name != "(standard input)" => path,
+        _ => return text,
+    };
+    let url = template
+        .replace("{path}", &encode_path(&path.to_string_lossy()))
+        .replace("{line}", &line_number.to_string())
+        .replace("{column}", &column.unwrap_or(1).to_string());
+    hyperlink(&url, &text)
+}
+
+/// Returns the prefix written before each line of output when several files
+/// are searched: the file name followed by a `:`, or by a NUL byte with `--null`.
+/// Otherwise, returns an empty string. With `--hyperlink-format`, the file name
+/// links to `line_number` and `column`.
+fn file_name_prefix(
+    file_name: &str,
+    line_number: usize,
+    column: Option<usize>,
+    flags: &Flags,
+) -> String {
+    if !flags.with_filename {
+        return String::new();
+    }
+    let separator = if flags.null { "\0" } else { ":" };
+    let name = link_to_file(
+        colorize_file_name(file_name, flags),
+        file_name,
+        line_number,
+        column,
+        flags,
+    );
+    format!("{}{}", name, separator)
+}
+
+/// Returns the prefix written before each line of output: the file name as described
+/// by `file_name_prefix`, followed by the 1-based line number with `--line-number`,
+/// the 1-based column of the first match with `--column` and the 0-based byte offset
+/// of the line with `--byte-offset`. The numbers are separated by `:`, and with
+/// `--hyperlink-format` they link to the line, like the file name.
+///
+/// Lines without a match, like context lines, have no `column`.
+fn line_prefix(
+    file_name: &str,
+    line_number: usize,
+    byte_offset: usize,
+    column: Option<usize>,
+    flags: &Flags,
+) -> String {
+    let mut numbers: Vec<String> = Vec::with_capacity(3);
+    if flags.line_number {
+        numbers.push(Colors::colorize_pattern(
+            Colors::LineNumber,
+            &format!("{}", line_number),
+            flags,
+        ));
+    }
+    if let (true, Some(column)) = (flags.column, column) {
+        numbers.push(Colors::colorize_pattern(
+            Colors::LineNumber,
+            &format!("{}", column),
+            flags,
+        ));
+    }
+    if flags.byte_offset {
+        numbers.push(Colors::colorize_pattern(
+            Colors::LineNumber,
+            &format!("{}", byte_offset),
+            flags,
+        ));
+    }
+
+    let mut prefix = file_name_prefix(file_name, line_number, column, flags);
+    if !numbers.is_empty() {
+        prefix.push_str(&link_to_file(
+            numbers.join(":"),
+            file_name,
+            line_number,
+            column,
+            flags,
+        ));
+        prefix.push_str(": ");
+    }
+    prefix
+}
+
+/// Highlights `file_name` with the file name color if colors are turned on.
+fn colorize_file_name(file_name: &str, flags: &Flags) -> String {
+    Colors::colorize_pattern(Colors::FileName, file_name, flags)
+}
+
+/// Returns `text[start..end]` with the parts of it covered by `segments` highlighted.
+/// `segments` are sorted `(start, end, color)` byte ranges of `text`, as returned by
+/// `Match::segments`, and may reach outside of `start..end`, in which case only the part
+/// within it is highlighted.
+///
+/// Every byte of `text[start..end]` is copied once, so the result only differs
+/// from it by the color codes around each segment.
+fn paint_segments(
+    text: &str,
+    (start, end): (usize, usize),
+    segments: &[(usize, usize, usize)],
+    flags: &Flags,
+) -> String {
+    let mut painted = String::with_capacity(end - start);
+    let mut last = start;
+    for &(segment_start, segment_end, color) in segments {
+        let (segment_start, segment_end) = (segment_start.max(start), segment_end.min(end));
+        if segment_start >= segment_end {
             continue;
         }
+        painted.push_str(&text[last..segment_start]);
+        painted.push_str(&Colors::colorize_pattern(
+            Colors::Match(color),
+            &text[segment_start..segment_end],
+            flags,
+        ));
+        last = segment_end;
+    }
+    painted.push_str(&text[last..end]);
+    painted
+}
+
+/// Highlights every match of `re` in `line`. Each pattern and capture group
+/// gets its own color, as described by `Match::segments`.
+fn colorize_matches(re: &Matcher, line: &str, flags: &Flags) -> String {
+    let segments: Vec<(usize, usize, usize)> = re
+        .find_iter(line)
+        .iter()
+        .flat_map(Match::segments)
+        .collect();
+    paint_segments(line, (0, line.len()), &segments, flags)
+}
+
+/// Returns a line containing a match as it should be printed. With `--replace`,
+/// every match is substituted by the template, where `$0`, `$1` or `${name}` stand for
+/// the text of the respective capture group. With `--color`, the matches are highlighted,
+/// or their replacements in the color of the pattern they come from.
+fn format_matched_line(re: &Matcher, line: &str, flags: &Flags) -> String {
+    match (&flags.replace, flags.colorize) {
+        (Some(_), true) => re.replace_all(line, |mat| format_match(&fold(line, flags), mat, flags)),
+        (Some(template), false) => re.replace_all(line, |mat| mat.expand(template)),
+        (None, true) => colorize_matches(re, line, flags),
+        (None, false) => line.to_string(),
+    }
+}
+
+/// Returns `mat`, found in `line`, as it should be printed on its own, substituted
+/// by the `--replace` template and highlighted like `format_matched_line` does.
+/// A match found in a line folded by `--normalize` or `--ignore-accents` is printed as
+/// it's written in the original line.
+fn format_match(line: &Folded, mat: &Match, flags: &Flags) -> String {
+    match &flags.replace {
+        Some(template) => {
+            Colors::colorize_pattern(Colors::Match(mat.color()), &mat.expand(template), flags)
+        }
+        None => paint_segments(
+            line.original,
+            line.original_span((mat.start, mat.end)),
+            &line.original_segments(&mat.segments()),
+            flags,
+        ),
     }
+}
 
-    // We need to iterate `matched_number` of times
-    // to find find trailing context lines for each.
-    for (j, matched_number) in matched_line_numbers.iter().enumerate() {
-        for (i, line) in lines.iter().enumerate() {
-            let upper_bound = matched_number + context_number;
-            if (i >= *matched_number) && (i <= upper_bound) {
-                if (i == *matched_number) && (flags.colorize) {
-                    let mut matched_line = line.clone();
-                    // colorize the matches
-                    re.find_iter(line).for_each(|matched| {
-                        matched_line = re
-                            .replace_all(
-                                &matched_line,
-                                Colors::colorize_pattern(Colors::Red, matched.as_str()),
-                            )
-                            .to_string()
-                    });
-                    matched_lines_with_number[j].push((i, matched_line))
-                } else {
-                    matched_lines_with_number[j].push((i, line.clone()));
+/// Splits `buffer` into lines on the record terminator chosen by `flags`,
+/// pairing each one with the byte offset it starts at within `buffer`.
+/// Like `Lines`, a `\r` right before a newline terminator is stripped.
+fn lines_with_offsets<'b>(buffer: &'b str, flags: &Flags) -> Vec<(usize, &'b str)> {
+    let terminator = flags.record_terminator() as char;
+    let mut offset = 0;
+    buffer
+        .split_inclusive(terminator)
+        .map(|line| {
+            let start = offset;
+            offset += line.len();
+            match line.strip_suffix(terminator) {
+                Some(line) if terminator == '\n' => {
+                    (start, line.strip_suffix('\r').unwrap_or(line))
                 }
+                stripped => (start, stripped.unwrap_or(line)),
+            }
+        })
+        .collect()
+}
+
+/// Returns the index of the line from `lines` that contains the byte at `offset`.
+fn line_index_of(lines: &[(usize, &str)], offset: usize) -> usize {
+    lines
+        .partition_point(|&(start, _)| start <= offset)
+        .saturating_sub(1)
+}
+
+/// Returns the indices of every line spanned by a match of `re` in `buffer`,
+/// in ascending order and without duplicates.
+fn multiline_matched_lines(buffer: &str, lines: &[(usize, &str)], re: &Matcher) -> Vec<usize> {
+    let mut matched: Vec<usize> = Vec::new();
+    // Byte offset right after the last line, excluding the final newline
+    let end_of_lines = lines.last().map_or(0, |&(start, line)| start + line.len());
+    for mat in re.find_iter(buffer) {
+        // An empty match after the final newline doesn't belong to any line
+        if mat.start > end_of_lines || lines.is_empty() {
+            break;
+        }
+        let first = line_index_of(lines, mat.start);
+        // A match ending with a newline doesn't spill into the next line
+        let last = line_index_of(lines, mat.end.saturating_sub(1).max(mat.start));
+        for i in first..=last {
+            if matched.last().is_none_or(|&l| l < i) {
+                matched.push(i);
             }
         }
     }
+    matched
+}
 
-    // Prints matches with context lines.
-    // Each group is separated by `group_separator`.
-    for (matched_line, is_last, is_first) in matched_lines_with_number
-        .iter()
-        .enumerate()
-        .map(|(index, m)| (m, index == matched_lines_with_number.len(), index == 0))
-    {
-        if !is_last && !is_first {
+/// Counters collected while searching, as reported by `--json` and `--stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Stats {
+    /// Lines containing at least one match
+    pub(crate) matched_lines: usize,
+    /// Individual matches, more than one of which can be on the same line
+    pub(crate) matches: usize,
+    /// Bytes read from the input
+    pub(crate) bytes_searched: usize,
+}
+
+impl Stats {
+    /// Counts a selected `line`, along with every match starting on it.
+    /// Lines selected with `--invert-match` have no matches.
+    fn add_line(&mut self, line: &Line, flags: &Flags) {
+        self.matched_lines += 1;
+        if line.is_match(flags) {
+            self.matches += line.matches.len();
+        }
+    }
+
+    /// Adds the counters of `other` to these.
+    fn add(&mut self, other: &Stats) {
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+        self.bytes_searched += other.bytes_searched;
+    }
+
+    /// Formats the counters as a JSON object.
+    fn to_json(self) -> String {
+        format!(
+            r#"{{"matched_lines":{},"matches":{},"bytes_searched":{}}}"#,
+            self.matched_lines, self.matches, self.bytes_searched
+        )
+    }
+}
+
+/// Prints the statistics collected with `--stats`. When several files are
+/// searched, the statistics of each of them come first, then those of the whole search.
+fn print_stats(
+    file_stats: &[(String, Stats)],
+    totals: &Stats,
+    files_matched: usize,
+    elapsed: Duration,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    writeln!(writer)?;
+    if file_stats.len() > 1 {
+        for (file_name, stats) in file_stats {
             writeln!(
                 writer,
-                "{}",
-                Colors::colorize_pattern(Colors::Blue, group_separator)
-            )?
+                "{}: {} matched lines, {} matches, {} bytes searched",
+                file_name, stats.matched_lines, stats.matches, stats.bytes_searched
+            )?;
         }
-        if flags.line_number {
-            for (i, line) in matched_line.iter() {
-                writeln!(
-                    writer,
-                    "{}: {}",
-                    Colors::colorize_pattern(Colors::Green, &format!("{}", i + 1)),
-                    line
-                )?
-            }
-        } else {
-            for (_, line) in matched_line {
-                writeln!(writer, "{}", line)?;
-            }
+        writeln!(writer)?;
+    }
+    writeln!(writer, "{} matched lines", totals.matched_lines)?;
+    writeln!(writer, "{} matches", totals.matches)?;
+    writeln!(writer, "{} files contained matches", files_matched)?;
+    writeln!(writer, "{} files searched", file_stats.len())?;
+    writeln!(writer, "{} bytes searched", totals.bytes_searched)?;
+    writeln!(
+        writer,
+        "{:.6} seconds spent searching",
+        elapsed.as_secs_f64()
+    )?;
+    writer.flush()
+}
+
+/// Searches `path` for matches of `re`, checking whether it's the standard input
+/// stream or a file, and calls `choose_process` accordingly. Returns the name of the
+/// input along with the statistics of the search.
+///
+/// With `--json`, the events of the input are preceded by a `begin` event and
+/// followed by an `end` event with the statistics of the search, or by an `error`
+/// event if the file can't be read through.
+///
+/// With `--in-place`, the file is rewritten by `rewrite_file` instead, and
+/// nothing is returned.
+fn search_path(
+    path: &Path,
+    re: &Matcher,
+    flags: &Flags,
+    context: ContextKind,
+    group_separator: &str,
+) -> Result<Option<(String, Stats)>, CliError> {
+    if flags.in_place {
+        rewrite_file(path, re, flags, getwriter!())?;
+        return Ok(None);
+    }
+    let (reader, file_name): (Box<dyn BufRead>, String) = if path == Path::new("STDIN") {
+        let stdin = io::stdin();
+        (
+            Box::new(BufReader::new(stdin.lock())),
+            "(standard input)".to_string(),
+        )
+    } else {
+        let file = File::open(path)
+            .map_err(|err| CliError::file(&path.display().to_string(), "open", err))?;
+        (Box::new(BufReader::new(file)), path.display().to_string())
+    };
+    let mut writer = getwriter!();
+    if flags.json {
+        writeln!(
+            writer,
+            r#"{{"type":"begin","data":{{"path":{}}}}}"#,
+            json_string(&file_name)
+        )?;
+    }
+    let mut reader = CountingReader::new(reader);
+    let stats = match choose_process(
+        &mut reader,
+        re.clone(),
+        &mut writer,
+        flags,
+        context,
+        group_separator,
+        &file_name,
+    ) {
+        Ok(stats) => stats,
+        Err(err @ CliError::File { .. }) if flags.json => {
+            writeln!(
+                writer,
+                r#"{{"type":"error","data":{{"path":{},"message":{}}}}}"#,
+                json_string(&file_name),
+                json_string(&err.to_string())
+            )?;
+            writer.flush()?;
+            return Err(err);
         }
+        Err(err) => return Err(err),
+    };
+    let stats = Stats {
+        bytes_searched: reader.bytes_read,
+        ..stats
+    };
+    if flags.json {
+        writeln!(
+            writer,
+            r#"{{"type":"end","data":{{"path":{},"stats":{}}}}}"#,
+            json_string(&file_name),
+            stats.to_json()
+        )?;
     }
     writer.flush()?;
-    Ok(())
+    Ok(Some((file_name, stats)))
 }
 
-/// Prints leading context lines with or without line numbers.
-/// Each group of match and its context is separated by `group_separator`.
-fn print_with_before_context<T: BufRead + Sized>(
-    reader: T,
-    re: Regex,
+/// Searches each of `paths` for any of `patterns` with `search_path`.
+///
+/// A file that can't be opened or read doesn't stop the search: the error is written
+/// to the standard error stream, unless `--no-messages` is given, and the search goes
+/// on with the next file. Returns whether every file could be searched. Any other
+/// error, like a pattern that doesn't compile, stops the search right away.
+///
+/// With `--json`, a final `summary` event adds up the statistics of all the inputs.
+/// With `--stats`, the statistics are printed by `print_stats` at the end instead.
+pub(crate) fn prepare_and_choose(
+    patterns: &[&str],
+    paths: &[&Path],
     flags: &Flags,
-    context_number: usize,
+    context: ContextKind,
     group_separator: &str,
-    mut writer: impl Write,
-) -> Result<(), CliError> {
-    let lines = reader.lines().collect::<std::io::Result<Vec<String>>>()?;
-
-    let mut matched_line_numbers: Vec<usize> = Vec::with_capacity(lines.len());
-    let mut matched_lines_with_number: Vec<Vec<(usize, String)>> = Vec::with_capacity(lines.len());
-
-    for (i, line_) in lines.iter().enumerate() {
-        if re.find(line_).is_some() {
-            matched_line_numbers.push(i);
-            let v = Vec::with_capacity(context_number + 1);
-            matched_lines_with_number.push(v);
-        } else {
-            continue;
+) -> Result<bool, CliError> {
+    let re = compile_patterns(patterns, flags)?;
+    let started = Instant::now();
+    let mut totals = Stats::default();
+    let mut files_matched = 0;
+    let mut failed = false;
+    let mut file_stats: Vec<(String, Stats)> = Vec::with_capacity(paths.len());
+    for path in paths {
+        match search_path(path, &re, flags, context, group_separator) {
+            Ok(Some((file_name, stats))) => {
+                if stats.matched_lines > 0 {
+                    files_matched += 1;
+                }
+                totals.add(&stats);
+                file_stats.push((file_name, stats));
+            }
+            Ok(None) => (),
+            Err(err @ CliError::File { .. }) => {
+                failed = true;
+                if !flags.no_messages {
+                    writeln!(io::stderr(), "error: {}", err)?;
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    if flags.json {
+        let mut writer = getwriter!();
+        writeln!(
+            writer,
+            r#"{{"type":"summary","data":{{"elapsed_secs":{},"files_searched":{},"files_matched":{},"stats":{}}}}}"#,
+            started.elapsed().as_secs_f64(),
+            file_stats.len(),
+            files_matched,
+            totals.to_json()
+        )?;
+        writer.flush()?;
+    } else if flags.stats {
+        print_stats(
+            &file_stats,
+            &totals,
+            files_matched,
+            started.elapsed(),
+            getwriter!(),
+        )?;
+    }
+    Ok(!failed)
+}
+
+/// A line of input, as it goes through the search pipeline of `choose_process`.
+struct Line {
+    /// 0-based index of the line within the input
+    index: usize,
+    /// 0-based byte offset the line starts at within the input
+    offset: usize,
+    text: String,
+    /// Whether the line is selected: whether it matches, or doesn't with `--invert-match`
+    selected: bool,
+    /// Byte spans of the matches starting on the line, within `text`. A match
+    /// spanning several lines in multiline mode is cut short at the end of the line
+    matches: Vec<(usize, usize)>,
+    /// 1-based column printed before the line with `--column`. That's where the first
+    /// match on the line starts, or 1 on a line continuing a match from a previous one
+    column: Option<usize>,
+    /// The parts of the line highlighted with `--color`, as `Match::segments` returns
+    /// them but relative to `text`
+    segments: Vec<(usize, usize, usize)>,
+    /// With `--only-matching`, the non-empty matches starting on the line as they're
+    /// printed on their own by `format_match`, paired with where they start within `text`.
+    /// A match spanning several lines in multiline mode is printed whole
+    only_matching: Vec<(usize, String)>,
+}
+
+impl Line {
+    /// Returns whether the line is selected because it matches, rather than
+    /// because it doesn't with `--invert-match`.
+    fn is_match(&self, flags: &Flags) -> bool {
+        self.selected && !flags.invert_match
+    }
+}
+
+/// Returns whether `line` matches: whether it satisfies the `--query`, if there's one,
+/// or contains a match of `re` otherwise.
+pub(crate) fn line_matches(re: &Matcher, line: &str, flags: &Flags) -> bool {
+    match &flags.query {
+        Some(query) => query.is_match(line),
+        None => re.is_match(line),
+    }
+}
+
+/// Searches the line `text` for matches of `re`, or for the `--query`. With `--normalize`
+/// or `--ignore-accents`, the folded line is searched, and the matches are mapped back to `text`.
+fn search_line(re: &Matcher, index: usize, offset: usize, text: String, flags: &Flags) -> Line {
+    let folded = fold(&text, flags);
+    let matched = line_matches(re, &folded.text, flags);
+    let found = match matched {
+        true => re.find_iter(&folded.text),
+        false => Vec::new(),
+    };
+    let matches: Vec<(usize, usize)> = found
+        .iter()
+        .map(|mat| folded.original_span((mat.start, mat.end)))
+        .collect();
+    let segments = match flags.colorize {
+        true => {
+            folded.original_segments(&found.iter().flat_map(Match::segments).collect::<Vec<_>>())
         }
+        false => Vec::new(),
+    };
+    let only_matching = match flags.only_matching {
+        true => found
+            .iter()
+            .filter(|mat| !mat.as_str().is_empty())
+            .map(|mat| {
+                let (start, _) = folded.original_span((mat.start, mat.end));
+                (start, format_match(&folded, mat, flags))
+            })
+            .collect(),
+        false => Vec::new(),
+    };
+    Line {
+        index,
+        offset,
+        selected: matched != flags.invert_match,
+        column: matches.first().map(|&(start, _)| start + 1),
+        matches,
+        segments,
+        only_matching,
+        text,
     }
+}
 
-    for (j, matched_number) in matched_line_numbers.iter().enumerate() {
-        for (i, line) in lines.iter().enumerate() {
-            // starting point is always positive
-            // this handles the case where a match exists on the first line
-            let starting_point = matched_number.saturating_sub(context_number);
-            if (i >= starting_point) && (i <= *matched_number) {
-                if (i == *matched_number) && (flags.colorize) {
-                    let mut matched_line = line.clone();
-                    //colorize the matches
-                    re.find_iter(line).for_each(|matched| {
-                        matched_line = re
-                            .replace_all(
-                                &matched_line,
-                                Colors::colorize_pattern(Colors::Red, matched.as_str()),
-                            )
-                            .to_string()
-                    });
-                    matched_lines_with_number[j].push((i, matched_line))
-                } else {
-                    matched_lines_with_number[j].push((i, line.clone()));
+/// Searches the whole of `reader` at once for matches of `re`, so that matches can
+/// cross line boundaries, and splits it into lines. Every line spanned by a match
+/// is a matching line, and the matches are highlighted piece by piece on each of them.
+fn search_buffer<T: BufRead + Sized>(
+    mut reader: T,
+    re: &Matcher,
+    flags: &Flags,
+) -> io::Result<Vec<Line>> {
+    let mut buffer = String::new();
+    reader.read_to_string(&mut buffer)?;
+    // Like `Lines` does, a `\r` right before a newline terminator is stripped, but from the
+    // whole buffer so that patterns like `,2$` or `a\nb` match. The offsets within the
+    // stripped buffer of the newlines it was before map offsets back to the input
+    let mut stripped: Vec<usize> = Vec::new();
+    if flags.record_terminator() == b'\n' && buffer.contains("\r\n") {
+        let mut without_cr = String::with_capacity(buffer.len());
+        for line in buffer.split_inclusive('\n') {
+            match line.strip_suffix("\r\n") {
+                Some(line) => {
+                    without_cr.push_str(line);
+                    stripped.push(without_cr.len());
+                    without_cr.push('\n');
                 }
+                None => without_cr.push_str(line),
+            }
+        }
+        buffer = without_cr;
+    }
+    let lines = lines_with_offsets(&buffer, flags);
+    let spanned = multiline_matched_lines(&buffer, &lines, re);
+    let found = re.find_iter(&buffer);
+    let segments: Vec<(usize, usize, usize)> = found.iter().flat_map(Match::segments).collect();
+    // Byte offset right after the last line, excluding the final newline
+    let end_of_lines = lines.last().map_or(0, |&(start, line)| start + line.len());
+    let mut matches: Vec<Vec<(usize, usize)>> = vec![Vec::new(); lines.len()];
+    let mut only_matching: Vec<Vec<(usize, String)>> = vec![Vec::new(); lines.len()];
+    for mat in &found {
+        if !mat.as_str().is_empty() && mat.start <= end_of_lines {
+            let i = line_index_of(&lines, mat.start);
+            let (line_start, line) = lines[i];
+            // A match can start on the terminator of the line, which isn't part of its text
+            let (start, end) = (mat.start - line_start, mat.end - line_start);
+            matches[i].push((start.min(line.len()), end.min(line.len())));
+            if flags.only_matching {
+                let whole = Folded::unchanged(&buffer);
+                let printed = format_match(&whole, mat, flags);
+                only_matching[i].push((start.min(line.len()), printed));
             }
         }
     }
 
-    for (matched_line, is_last, is_first) in matched_lines_with_number
+    Ok(lines
         .iter()
+        .zip(matches.into_iter().zip(only_matching))
         .enumerate()
-        .map(|(index, m)| (m, index == matched_lines_with_number.len(), index == 0))
-    {
-        if !is_last && !is_first {
-            writeln!(
+        .map(|(i, (&(line_start, line), (matches, only_matching)))| {
+            let line_end = line_start + line.len();
+            let matched = spanned.binary_search(&i).is_ok();
+            let overlapping: Vec<(usize, usize, usize)> = segments
+                .iter()
+                .filter(|&&(start, end, _)| start <= line_end && end > line_start)
+                .map(|&(start, end, color)| {
+                    (
+                        start.max(line_start) - line_start,
+                        end.min(line_end) - line_start,
+                        color,
+                    )
+                })
+                .collect();
+            Line {
+                index: i,
+                offset: line_start + stripped.partition_point(|&newline| newline < line_start),
+                text: line.to_string(),
+                selected: matched != flags.invert_match,
+                column: overlapping
+                    .first()
+                    .map(|&(start, _, _)| start + 1)
+                    .filter(|_| matched),
+                matches,
+                segments: overlapping,
+                only_matching,
+            }
+        })
+        .collect())
+}
+
+/// Returns a selected line as it should be printed. Matching lines are formatted by
+/// `format_matched_line`, or highlighted by their segments when searching the whole
+/// input at once. Other lines are printed as they are.
+fn format_line(re: &Matcher, line: &Line, flags: &Flags) -> String {
+    match (line.is_match(flags), flags.multiline) {
+        (true, false) if flags.replace.is_some() => format_matched_line(re, &line.text, flags),
+        (true, _) => paint_segments(&line.text, (0, line.text.len()), &line.segments, flags),
+        (false, _) => line.text.clone(),
+    }
+}
+
+/// Prints a line of output, with the prefix built by `line_prefix`. Selected lines
+/// are formatted by `format_line`, while context lines are printed as they are.
+///
+/// With `--only-matching`, every non-empty match on a matching line is printed on
+/// a line of its own instead, and other lines aren't printed at all.
+fn print_line(
+    re: &Matcher,
+    line: &Line,
+    flags: &Flags,
+    file_name: &str,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    if flags.only_matching {
+        if !line.is_match(flags) {
+            return Ok(());
+        }
+        for (start, mat) in &line.only_matching {
+            write!(
                 writer,
-                "{}",
-                Colors::colorize_pattern(Colors::Blue, group_separator)
+                "{}{}{}",
+                line_prefix(
+                    file_name,
+                    line.index + 1,
+                    line.offset + start,
+                    Some(start + 1),
+                    flags
+                ),
+                mat,
+                flags.line_terminator()
             )?;
         }
-        if flags.line_number {
-            for (i, line) in matched_line.iter() {
-                writeln!(
-                    writer,
-                    "{}: {}",
-                    Colors::colorize_pattern(Colors::Green, &format!("{}", i + 1)),
-                    line
-                )?;
-            }
-        } else {
-            for (_, line) in matched_line.iter() {
-                writeln!(writer, "{}", line)?;
-            }
-        }
+        return Ok(());
+    }
+    let text = match line.selected {
+        true => format_line(re, line, flags),
+        false => line.text.clone(),
+    };
+    write!(
+        writer,
+        "{}{}{}",
+        line_prefix(file_name, line.index + 1, line.offset, line.column, flags),
+        text,
+        flags.line_terminator()
+    )
+}
+
+/// Prints every match on a selected line in the `path:line:column:text` format that
+/// editors read into their quickfix lists. A line with several matches is printed
+/// once for each of them, with the column of that match. Inverted matches
+/// have no column, so they're printed with the column of the line's start.
+fn print_vimgrep_line(
+    re: &Matcher,
+    line: &Line,
+    flags: &Flags,
+    file_name: &str,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let columns: Vec<usize> = match line.is_match(flags) {
+        true => line.matches.iter().map(|&(start, _)| start + 1).collect(),
+        false => vec![1],
+    };
+    let printed_line = format_line(re, line, flags);
+    for column in columns {
+        write!(
+            writer,
+            "{}:{}:{}:{}{}",
+            file_name,
+            line.index + 1,
+            column,
+            printed_line,
+            flags.line_terminator()
+        )?;
     }
-    writer.flush()?;
     Ok(())
 }
 
-/// Prints leading and trailing context lines with or without line numbers.
-/// Each group of match and its context is separated by `group_separator`.
-fn print_with_context<T: BufRead + Sized>(
-    reader: T,
-    re: Regex,
+/// Prints `line` as a JSON object: a `match` event if it's selected, or a `context`
+/// event otherwise. Events carry the line, its 1-based number, its 0-based byte offset
+/// and the byte spans of the matches starting on it.
+fn print_json_line(
+    line: &Line,
     flags: &Flags,
-    context_number: usize,
-    group_separator: &str,
-    mut writer: impl Write,
-) -> Result<(), CliError> {
-    let lines = reader.lines().collect::<std::io::Result<Vec<String>>>()?;
-
-    let mut matched_line_numbers: Vec<usize> = Vec::with_capacity(lines.len());
-    let mut matched_lines_with_number: Vec<Vec<(usize, String)>> = Vec::with_capacity(lines.len());
-
-    for (i, line_) in lines.iter().enumerate() {
-        if re.find(line_).is_some() {
-            matched_line_numbers.push(i);
-            let v = Vec::with_capacity(context_number + 1);
-            matched_lines_with_number.push(v);
-        } else {
-            continue;
+    file_name: &str,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let submatches: Vec<String> = match line.is_match(flags) {
+        true => line
+            .matches
+            .iter()
+            .map(|&(start, end)| {
+                format!(
+                    r#"{{"match":{},"start":{},"end":{}}}"#,
+                    json_string(&line.text[start..end]),
+                    start,
+                    end
+                )
+            })
+            .collect(),
+        false => Vec::new(),
+    };
+    writeln!(
+        writer,
+        r#"{{"type":"{}","data":{{"path":{},"line":{},"line_number":{},"absolute_offset":{},"submatches":[{}]}}}}"#,
+        if line.selected { "match" } else { "context" },
+        json_string(file_name),
+        json_string(&line.text),
+        line.index + 1,
+        line.offset,
+        submatches.join(",")
+    )
+}
+
+/// How the lines around each selected line are printed.
+enum Context {
+    /// Each selected line is printed on its own, with nothing between them
+    None,
+    /// Each selected line is printed with the given number of lines before and
+    /// after it, as a group of its own
+    Lines(usize, usize),
+    /// Each block containing a selected line is printed as a group, once
+    /// no matter how many selected lines it holds. Blocks are delimited as
+    /// described by `block_bounds`
+    Block(Option<Regex>),
+    /// Each selected line matching one of the two patterns searched for is printed
+    /// along with the lines leading up to it from the closest line at most the given
+    /// number of lines before it that matches the other pattern, as a group of its own
+    Near(usize),
+}
+
+/// Returns whether `line` starts a new block after the lines of `block`, as
+/// described by `block_bounds`.
+fn starts_block(line: &Line, block: &[Line], block_start: Option<&Regex>) -> bool {
+    let last = match block.last() {
+        Some(last) => last,
+        None => return false,
+    };
+    match block_start {
+        Some(start_re) => start_re.is_match(&line.text),
+        None => {
+            let is_blank = |line: &Line| line.text.trim().is_empty();
+            is_blank(line) || is_blank(last)
         }
     }
+}
+
+/// Returns the lines of `window` from `before` lines before the line at index `selected`
+/// up to `after` lines after it.
+fn lines_around(
+    window: &VecDeque<Line>,
+    selected: usize,
+    before: usize,
+    after: usize,
+) -> Vec<&Line> {
+    window
+        .iter()
+        .filter(|line| {
+            line.index.saturating_add(before) >= selected
+                && line.index <= selected.saturating_add(after)
+        })
+        .collect()
+}
 
-    for (j, matched_number) in matched_line_numbers.iter().enumerate() {
-        // Upper and lower bounds of leading and trailing contxt lines
-        // respectively, for each matching line
-        let lower_bound = matched_number.saturating_sub(context_number);
-        let upper_bound = matched_number + context_number;
-        for (i, line) in lines.iter().enumerate() {
-            if (i >= lower_bound) && (i <= upper_bound) {
-                if (i == *matched_number) && (flags.colorize) {
-                    let mut matched_line = line.clone();
-                    //colorize the matches
-                    re.find_iter(line).for_each(|matched| {
-                        matched_line = re
-                            .replace_all(
-                                &matched_line,
-                                Colors::colorize_pattern(Colors::Red, matched.as_str()),
-                            )
-                            .to_string()
-                    });
-                    matched_lines_with_number[j].push((i, matched_line))
-                } else {
-                    matched_lines_with_number[j].push((i, line.clone()));
+/// Prints the selected lines coming out of `lines` along with their context.
+/// Each group of selected line and its context is separated by `group_separator`.
+///
+/// Lines are streamed through a window holding just enough of them for the context
+/// of each group. Groups can overlap, in which case a line can be printed more than once.
+///
+/// With `--near`, a group is printed whenever a line matches one of the patterns and
+/// one of the lines in the window before it matches the other, and only the selected
+/// lines of the groups are counted in the statistics.
+///
+/// With `--json`, the lines of each group are printed by `print_json_line` instead,
+/// without separators, and each line only once even when groups overlap.
+fn print_with_context(
+    lines: impl Iterator<Item = Result<Line, CliError>>,
+    re: &Matcher,
+    context: &Context,
+    flags: &Flags,
+    file_name: &str,
+    group_separator: &str,
+    mut writer: impl Write,
+) -> Result<Stats, CliError> {
+    let mut stats = Stats::default();
+    let mut groups = 0;
+    // Index of the first line that isn't printed as a JSON event yet
+    let mut unprinted = 0;
+    let mut print_group = |group: Vec<&Line>, mut writer: &mut dyn Write| -> io::Result<()> {
+        if flags.json {
+            for line in group {
+                if line.index >= unprinted {
+                    print_json_line(line, flags, file_name, &mut writer)?;
+                    unprinted = line.index + 1;
                 }
             }
+            return Ok(());
         }
-    }
-    for (matched_line, is_last, is_first) in matched_lines_with_number
-        .iter()
-        .enumerate()
-        .map(|(index, m)| (m, index == matched_lines_with_number.len(), index == 0))
-    {
-        if !is_last && !is_first {
-            writeln!(
+        if groups > 0 && !matches!(context, Context::None) {
+            write!(
                 writer,
-                "{}",
-                Colors::colorize_pattern(Colors::Blue, group_separator)
+                "{}{}",
+                Colors::colorize_pattern(Colors::Separator, group_separator, flags),
+                flags.line_terminator()
             )?;
         }
-        if flags.line_number {
-            for (i, line) in matched_line.iter() {
-                writeln!(
-                    writer,
-                    "{}: {}",
-                    Colors::colorize_pattern(Colors::Green, &format!("{}", i + 1)),
-                    line
-                )?;
+        groups += 1;
+        for line in group {
+            print_line(re, line, flags, file_name, &mut writer)?;
+        }
+        Ok(())
+    };
+
+    let mut window: VecDeque<Line> = VecDeque::new();
+    // Indices of the selected lines whose group is yet to be printed
+    let mut pending: VecDeque<usize> = VecDeque::new();
+    let (before, after) = match context {
+        Context::Lines(before, after) => (*before, *after),
+        _ => (0, 0),
+    };
+
+    // Which of the two patterns of `--near` each line in the window matches
+    let mut near_patterns: VecDeque<[bool; 2]> = VecDeque::new();
+    // Index of the first line that isn't counted in the statistics yet
+    let mut uncounted = 0;
+
+    for line in lines {
+        let line = line?;
+        if let Context::Near(within) = context {
+            let mut patterns = [false; 2];
+            if line.selected {
+                for mat in re.find_iter(&fold(&line.text, flags).text) {
+                    patterns[mat.pattern().min(1)] = true;
+                }
+            }
+            let index = line.index;
+            window.push_back(line);
+            near_patterns.push_back(patterns);
+            if window.len() > within.saturating_add(1) {
+                window.pop_front();
+                near_patterns.pop_front();
+            }
+            // The closest line matching the other pattern, which is the line itself if it matches both
+            let start = near_patterns
+                .iter()
+                .rposition(|p| (patterns[0] && p[1]) || (patterns[1] && p[0]));
+            if let Some(start) = start {
+                let group: Vec<&Line> = window.iter().skip(start).collect();
+                for line in group
+                    .iter()
+                    .filter(|line| line.selected && line.index >= uncounted)
+                {
+                    stats.add_line(line, flags);
+                }
+                uncounted = index + 1;
+                print_group(group, &mut writer)?;
+            }
+            continue;
+        }
+        if line.selected {
+            stats.add_line(&line, flags);
+        }
+        if let Context::Block(block_start) = context {
+            if starts_block(&line, window.make_contiguous(), block_start.as_ref()) {
+                if window.iter().any(|line| line.selected) {
+                    print_group(window.iter().collect(), &mut writer)?;
+                }
+                window.clear();
             }
-        } else {
-            for (_, line) in matched_line.iter() {
-                writeln!(writer, "{}", line)?;
+            window.push_back(line);
+            continue;
+        }
+
+        if line.selected {
+            pending.push_back(line.index);
+        }
+        let index = line.index;
+        window.push_back(line);
+        if window.len() > before.saturating_add(after).saturating_add(1) {
+            window.pop_front();
+        }
+        // A group is printed as soon as its last line of trailing context is read
+        while let Some(selected) = pending.pop_front() {
+            if selected.saturating_add(after) > index {
+                pending.push_front(selected);
+                break;
             }
+            print_group(lines_around(&window, selected, before, after), &mut writer)?;
         }
     }
-    writer.flush()?;
-    Ok(())
-}
-
-/// Checks whether `path` is the standard input stream or a file
-/// and calls `choose_process` accordingly.
-pub(crate) fn prepare_and_choose(
-    needle: (&str, bool),
-    path: &std::path::Path,
-    flags: &Flags,
-    context: ContextKind,
-    group_separator: &str,
-) -> Result<(), CliError> {
-    let re = compile_regex(needle.0, needle.1)?;
-    if path == Path::new("STDIN") {
-        let stdin = io::stdin();
-        let stdin_reader = BufReader::new(stdin.lock());
-        let writer = getwriter!();
-        choose_process(stdin_reader, re, writer, flags, context, group_separator)?;
-    } else {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let writer = getwriter!();
-        choose_process(reader, re, writer, flags, context, group_separator)?;
+    // The groups running up to the end of the input
+    if let Context::Block(_) = context {
+        if window.iter().any(|line| line.selected) {
+            print_group(window.iter().collect(), &mut writer)?;
+        }
     }
-    Ok(())
+    while let Some(selected) = pending.pop_front() {
+        print_group(lines_around(&window, selected, before, after), &mut writer)?;
+    }
+    writer.flush()?;
+    Ok(stats)
 }
 
-/// Checks the runtime arguments provided by the user and
-/// calls the appropriate method.
+/// Checks the runtime arguments provided by the user and searches `reader` through
+/// a single pipeline: its lines are searched, all at once by `search_buffer` in
+/// multiline mode or one by one otherwise, and the selected ones are passed on
+/// to `process_lines`. Returns the statistics of the search.
 fn choose_process<T: BufRead + Sized>(
-    mut reader: T,
-    re: regex::Regex,
+    reader: T,
+    re: Matcher,
     writer: impl Write,
     flags: &Flags,
     context: ContextKind,
     group_separator: &str,
-) -> Result<(), CliError> {
-    if flags.count {
-        println!("{}", count_matches(reader, re));
-        return Ok(());
-    } else if flags.invert_match {
-        print_invert_matches(reader, re, flags, writer)?;
-        return Ok(());
-    }
-    match context {
+    file_name: &str,
+) -> Result<Stats, CliError> {
+    let context = match context {
         ContextKind::After(after_ctx) => {
-            let after_ctx = parse_context_number(after_ctx)?;
-            print_with_after_context(&mut reader, re, flags, after_ctx, group_separator, writer)?
+            Context::Lines(0, parse_context_number(after_ctx, "--after-context")?)
         }
         ContextKind::Before(before_ctx) => {
-            let before_ctx = parse_context_number(before_ctx)?;
-            print_with_before_context(&mut reader, re, flags, before_ctx, group_separator, writer)?
+            Context::Lines(parse_context_number(before_ctx, "--before-context")?, 0)
         }
-
         ContextKind::AfterAndBefore(both_ctx) => {
-            let both_ctx = parse_context_number(both_ctx)?;
-            print_with_context(&mut reader, re, flags, both_ctx, group_separator, writer)?
+            let both_ctx = parse_context_number(both_ctx, "--context")?;
+            Context::Lines(both_ctx, both_ctx)
         }
-        ContextKind::None => print_matches(reader, re, flags, writer)?,
+        ContextKind::Paragraph(block_start) => Context::Block(match block_start {
+            Some(p) => Some(compile_regex(p, flags)?),
+            None => None,
+        }),
+        ContextKind::Near(within) => Context::Near(parse_context_number(within, "--within")?),
+        ContextKind::None => Context::None,
     };
-    Ok(())
+
+    // Errors reading the input are about the file, unlike those writing the output
+    let read_error = |err| CliError::file(file_name, "read", err);
+    if flags.multiline {
+        let lines = search_buffer(reader, &re, flags)
+            .map_err(read_error)?
+            .into_iter()
+            .map(Ok);
+        process_lines(
+            lines,
+            &re,
+            writer,
+            flags,
+            &context,
+            group_separator,
+            file_name,
+        )
+    } else {
+        let lines = lines_with_byte_offsets(reader, flags)
+            .enumerate()
+            .map(|(i, line)| {
+                line.map(|(offset, text)| search_line(&re, i, offset, text, flags))
+                    .map_err(read_error)
+            });
+        process_lines(
+            lines,
+            &re,
+            writer,
+            flags,
+            &context,
+            group_separator,
+            file_name,
+        )
+    }
 }
 
-/// Prints the lines containing the matches found.
-/// Based on the status of the `line_number` field of Flag struct,
-/// also prints the 1-based line number preceeding each line.
-fn print_matches<T: BufRead + Sized>(
-    reader: T,
-    re: Regex,
-    flags: &Flags,
+/// Does what the runtime arguments ask for with the searched `lines`: prints the
+/// file name if any of them is selected with `--files-with-matches`, the number of
+/// selected lines or matches with `--count` or `--count-matches`, every match with
+/// `--vimgrep`, or else the selected lines with their context, as JSON events
+/// with `--json`.
+fn process_lines(
+    lines: impl Iterator<Item = Result<Line, CliError>>,
+    re: &Matcher,
     mut writer: impl Write,
-) -> Result<(), CliError> {
-    // `.lines()` returns an iterator over each line of `reader`, in the form of `io::Result::String`
-    // So a line would be an instance like this: `Ok(line)`
-    // `enumerate` gives us the (index, value) pair
-    let mut lines = reader.lines().enumerate();
-
-    // `.next()` on an iterator returns the item wrapped in an Option
-    // So Each `Some` variant of that option will hold the (index, value) pair
-    while let Some((i, Ok(line))) = lines.next() {
-        if re.find(&line).is_none() {
-            continue;
-        }
-        let match_iter = re.find_iter(&line);
-        let mut matched_line = line.clone();
-
-        match (flags.colorize, flags.line_number) {
-            (true, true) => {
-                // colorize the patterns
-                for mat in match_iter {
-                    /* matched_line = format!(
-                        "{}",
-                        re.replace_all(
-                            &matched_line,
-                            Colors::colorize_pattern(Colors::Red, mat.as_str())
-                        )
-                    ); */
-                    matched_line = format!(
-                        "{}: {}",
-                        Colors::colorize_pattern(Colors::Green, &format!("{}", i + 1)),
-                        re.replace_all(
-                            &matched_line,
-                            Colors::colorize_pattern(Colors::Red, mat.as_str())
-                        )
-                    );
-                }
-                // add colored line numbers
-                
-            }
-            (true, false) => {
-                for mat in match_iter {
-                    matched_line = format!(
-                        "{}",
-                        re.replace_all(
-                            &matched_line,
-                            Colors::colorize_pattern(Colors::Red, mat.as_str())
-                        )
-                    );
-                }
+    flags: &Flags,
+    context: &Context,
+    group_separator: &str,
+    file_name: &str,
+) -> Result<Stats, CliError> {
+    let mut stats = Stats::default();
+    if flags.json {
+        return print_with_context(
+            lines,
+            re,
+            context,
+            flags,
+            file_name,
+            group_separator,
+            writer,
+        );
+    } else if flags.files_with_matches {
+        // Reading stops at the first selected line, unless every line is counted for `--stats`
+        let mut printed = false;
+        for line in lines {
+            let line = line?;
+            if !line.selected {
+                continue;
             }
-            (false, true) => {
-                matched_line = format!(
-                    "{}: {}",
-                    Colors::colorize_pattern(Colors::Green, &format!("{}", i + 1)),
-                    matched_line
+            stats.add_line(&line, flags);
+            if !printed {
+                let terminator = if flags.null { "\0" } else { "\n" };
+                let name = link_to_file(
+                    colorize_file_name(file_name, flags),
+                    file_name,
+                    1,
+                    None,
+                    flags,
                 );
+                write!(writer, "{}{}", name, terminator)?;
+                printed = true;
+            }
+            if !flags.stats {
+                break;
             }
-            _ => (),
         }
-
-        writeln!(writer, "{}", matched_line)?;
-    }
-    writer.flush()?;
-    Ok(())
-}
-
-/// Prints the lines that doesn't contain the pattern.
-/// Based on the status of the `line_number` field of Flag struct,
-/// also prints the 1-based line number preceeding each line.
-fn print_invert_matches<T: BufRead + Sized>(
-    reader: T,
-    re: Regex,
-    flags: &Flags,
-    mut writer: impl Write,
-) -> Result<(), CliError> {
-    let mut lines = reader.lines().enumerate();
-    while let Some((i, Ok(line))) = lines.next() {
-        // don't do anything if match is found
-        if re.find(&line).is_some() {
-            continue;
+    } else if flags.count || flags.count_matches {
+        for line in lines {
+            let line = line?;
+            if line.selected {
+                stats.add_line(&line, flags);
+            }
+        }
+        // Inverted matches are whole lines, so there are no individual matches to count
+        let count = match flags.count_matches && !flags.invert_match {
+            true => stats.matches,
+            false => stats.matched_lines,
         };
-        if flags.line_number {
-            writeln!(
-                writer,
-                "{}: {}",
-                Colors::colorize_pattern(Colors::Green, &format!("{}", i + 1)),
-                line
-            )?;
-        } else {
-            writeln!(writer, "{}", line)?;
+        writeln!(
+            writer,
+            "{}{}",
+            file_name_prefix(file_name, 1, None, flags),
+            count
+        )?;
+    } else if flags.vimgrep {
+        for line in lines {
+            let line = line?;
+            if line.selected {
+                stats.add_line(&line, flags);
+                print_vimgrep_line(re, &line, flags, file_name, &mut writer)?;
+            }
         }
+    } else {
+        return print_with_context(
+            lines,
+            re,
+            context,
+            flags,
+            file_name,
+            group_separator,
+            writer,
+        );
     }
     writer.flush()?;
-    Ok(())
+    Ok(stats)
 }
 
 #[cfg(test)]
@@ -434,340 +1117,1290 @@ mod tests {
     use regex::RegexBuilder;
 
     use super::*;
+    use crate::core::color::ColorScheme;
+    use crate::core::normalize::Normalization;
+    use crate::core::query::Query;
     use crate::Flags;
     use std::fs::File;
     use std::io::BufReader;
 
-    fn test_inputs(pattern: &str) -> (BufReader<File>, Regex, Vec<u8>) {
+    fn test_inputs(pattern: &str) -> (BufReader<File>, Matcher, Vec<u8>) {
         let writer = Vec::new();
         let file = File::open("src/data/pessoa.txt").unwrap();
         let reader = BufReader::new(file);
-        let regex = RegexBuilder::new(pattern).build().unwrap();
+        let regex = RegexBuilder::new(pattern).build().unwrap().into();
 
         (reader, regex, writer)
     }
 
     #[test]
     fn number_of_matches() {
+        let flags = Flags {
+            count: true,
+            ..Flags::default()
+        };
         let (reader, regex, _) = test_inputs("like");
-        let number_of_matches = count_matches(reader, regex);
-        assert_eq!(number_of_matches, 5);
+        let stats = choose_process(
+            reader,
+            regex,
+            Vec::new(),
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(stats.matched_lines, 5);
+        assert_eq!(stats.matches, 5);
     }
 
     #[test]
-    fn print_matches_with_line_number() {
+    fn number_of_inverted_matches() {
         let flags = Flags {
-            count: false,
-            line_number: true,
-            colorize: false,
-            ignore_case: false,
-            invert_match: false,
+            count: true,
+            invert_match: true,
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("distress");
-        print_matches(reader, regex, &flags, &mut writer).unwrap();
-        assert_eq!(
-            writer,
-            "\u{1b}[32m6\u{1b}[39m: distresses me like a letter of farewell. I feel as if I’m always on the\n"
+        let (reader, regex, _) = test_inputs("like");
+        let stats = choose_process(
+            reader,
+            regex,
+            Vec::new(),
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(stats.matched_lines, 20);
+        assert_eq!(stats.matches, 0);
+    }
+
+    #[test]
+    fn count_matches_instead_of_lines() {
+        let flags = Flags {
+            count_matches: true,
+            with_filename: true,
+            ..Flags::default()
+        };
+        let (reader, regex, _) = test_inputs(r"\bour\b");
+        let stats = choose_process(
+            reader,
+            regex,
+            Vec::new(),
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!((stats.matched_lines, stats.matches), (2, 3));
+    }
+
+    #[test]
+    fn only_matching_with_column_and_byte_offset() {
+        let flags = Flags {
+            only_matching: true,
+            line_number: true,
+            column: true,
+            byte_offset: true,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs(r"\bour\b");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "24:13:1137: our
+24:44:1168: our
+25:41:1235: our\n"
+        );
+    }
+
+    #[test]
+    fn print_matches_with_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: true,
+            colorize: false,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("distress");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "6: distresses me like a letter of farewell. I feel as if I’m always on the\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn print_matches_without_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: false,
+            colorize: false,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("distress");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "distresses me like a letter of farewell. I feel as if I’m always on the\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn after_context_without_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: false,
+            colorize: false,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("distress");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::After("3"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "distresses me like a letter of farewell. I feel as if I’m always on the
+verge of waking up. I’m oppressed by the very self that encases me,
+asphyxiated by conclusions, and I’d gladly scream if my voice could
+reach somewhere. But there’s this heavy slumber that moves from one\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn after_context_with_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: true,
+            colorize: false,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("distress");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::After("3"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "6: distresses me like a letter of farewell. I feel as if I’m always on the
+7: verge of waking up. I’m oppressed by the very self that encases me,
+8: asphyxiated by conclusions, and I’d gladly scream if my voice could
+9: reach somewhere. But there’s this heavy slumber that moves from one\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn after_context_with_ten_char_words_and_color() {
+        let flags = Flags {
+            count: false,
+            line_number: false,
+            colorize: true,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs(r"\b\w{10}\b");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::After("2"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "confused landscape, along with \u{1b}[31meverything\u{1b}[39m else.
+
+In these times when an abyss opens up in my soul, the tiniest detail
+\u{1b}[34m####\u{1b}[39m
+\u{1b}[31mdistresses\u{1b}[39m me like a letter of farewell. I feel as if I’m always on the
+verge of waking up. I’m oppressed by the very self that encases me,
+asphyxiated by conclusions, and I’d gladly scream if my voice could
+\u{1b}[34m####\u{1b}[39m
+group of my \u{1b}[31msensations\u{1b}[39m to another, like drifting clouds that make the
+half-shaded grass of sprawling fields turn various colours of sun and
+green.
+\u{1b}[34m####\u{1b}[39m
+illusions or moments of calm, large hopes \u{1b}[31mchannelled\u{1b}[39m into the
+landscape, sorrows like closed rooms, certain voices, a huge weariness,
+the unwritten gospel.
+\u{1b}[34m####\u{1b}[39m
+We all have our vanity, and that vanity is our way of \u{1b}[31mforgetting\u{1b}[39m that
+there are other people with a soul like our own. My vanity consists of\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn before_context_without_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: false,
+            colorize: true,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("distress");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::Before("3"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "confused landscape, along with everything else.
+
+In these times when an abyss opens up in my soul, the tiniest detail
+\u{1b}[31mdistress\u{1b}[39mes me like a letter of farewell. I feel as if I’m always on the\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn before_context_with_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: true,
+            colorize: true,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("distress");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::Before("3"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "\u{1b}[32m3\u{1b}[39m: confused landscape, along with everything else.
+\u{1b}[32m4\u{1b}[39m: 
+\u{1b}[32m5\u{1b}[39m: In these times when an abyss opens up in my soul, the tiniest detail
+\u{1b}[32m6\u{1b}[39m: \u{1b}[31mdistress\u{1b}[39mes me like a letter of farewell. I feel as if I’m always on the\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn context_with_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: true,
+            colorize: true,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("like");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::AfterAndBefore("2"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "\u{1b}[32m4\u{1b}[39m: 
+\u{1b}[32m5\u{1b}[39m: In these times when an abyss opens up in my soul, the tiniest detail
+\u{1b}[32m6\u{1b}[39m: distresses me \u{1b}[31mlike\u{1b}[39m a letter of farewell. I feel as if I’m always on the
+\u{1b}[32m7\u{1b}[39m: verge of waking up. I’m oppressed by the very self that encases me,
+\u{1b}[32m8\u{1b}[39m: asphyxiated by conclusions, and I’d gladly scream if my voice could
+\u{1b}[34m####\u{1b}[39m
+\u{1b}[32m8\u{1b}[39m: asphyxiated by conclusions, and I’d gladly scream if my voice could
+\u{1b}[32m9\u{1b}[39m: reach somewhere. But there’s this heavy slumber that moves from one
+\u{1b}[32m10\u{1b}[39m: group of my sensations to another, \u{1b}[31mlike\u{1b}[39m drifting clouds that make the
+\u{1b}[32m11\u{1b}[39m: half-shaded grass of sprawling fields turn various colours of sun and
+\u{1b}[32m12\u{1b}[39m: green.
+\u{1b}[34m####\u{1b}[39m
+\u{1b}[32m12\u{1b}[39m: green.
+\u{1b}[32m13\u{1b}[39m: 
+\u{1b}[32m14\u{1b}[39m: I’m \u{1b}[31mlike\u{1b}[39m someone searching at random, not knowing what object he’s
+\u{1b}[32m15\u{1b}[39m: looking for nor where it was hidden. We play hide-and-seek with no
+\u{1b}[32m16\u{1b}[39m: one. There’s a transcendent trick in all of this, a fluid divinity we can
+\u{1b}[34m####\u{1b}[39m
+\u{1b}[32m19\u{1b}[39m: Yes, I reread these pages that represent worthless hours, brief
+\u{1b}[32m20\u{1b}[39m: illusions or moments of calm, large hopes channelled into the
+\u{1b}[32m21\u{1b}[39m: landscape, sorrows \u{1b}[31mlike\u{1b}[39m closed rooms, certain voices, a huge weariness,
+\u{1b}[32m22\u{1b}[39m: the unwritten gospel.
+\u{1b}[32m23\u{1b}[39m: 
+\u{1b}[34m####\u{1b}[39m
+\u{1b}[32m23\u{1b}[39m: 
+\u{1b}[32m24\u{1b}[39m: We all have our vanity, and that vanity is our way of forgetting that
+\u{1b}[32m25\u{1b}[39m: there are other people with a soul \u{1b}[31mlike\u{1b}[39m our own. My vanity consists of\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn context_without_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: false,
+            colorize: true,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("like");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::AfterAndBefore("2"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "
+In these times when an abyss opens up in my soul, the tiniest detail
+distresses me \u{1b}[31mlike\u{1b}[39m a letter of farewell. I feel as if I’m always on the
+verge of waking up. I’m oppressed by the very self that encases me,
+asphyxiated by conclusions, and I’d gladly scream if my voice could
+\u{1b}[34m####\u{1b}[39m
+asphyxiated by conclusions, and I’d gladly scream if my voice could
+reach somewhere. But there’s this heavy slumber that moves from one
+group of my sensations to another, \u{1b}[31mlike\u{1b}[39m drifting clouds that make the
+half-shaded grass of sprawling fields turn various colours of sun and
+green.
+\u{1b}[34m####\u{1b}[39m
+green.
+
+I’m \u{1b}[31mlike\u{1b}[39m someone searching at random, not knowing what object he’s
+looking for nor where it was hidden. We play hide-and-seek with no
+one. There’s a transcendent trick in all of this, a fluid divinity we can
+\u{1b}[34m####\u{1b}[39m
+Yes, I reread these pages that represent worthless hours, brief
+illusions or moments of calm, large hopes channelled into the
+landscape, sorrows \u{1b}[31mlike\u{1b}[39m closed rooms, certain voices, a huge weariness,
+the unwritten gospel.
+
+\u{1b}[34m####\u{1b}[39m
+
+We all have our vanity, and that vanity is our way of forgetting that
+there are other people with a soul \u{1b}[31mlike\u{1b}[39m our own. My vanity consists of\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn context_as_large_as_it_gets() {
+        let flags = Flags::default();
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex("two", &flags).unwrap());
+        let context = usize::MAX.to_string();
+        choose_process(
+            &b"one\ntwo\nthree\n"[..],
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::AfterAndBefore(&context),
+            "####",
+            "numbers.txt",
+        )
+        .unwrap();
+        assert_eq!(writer, b"one\ntwo\nthree\n".to_vec());
+    }
+
+    #[test]
+    fn paragraph_context_with_line_number() {
+        let flags = Flags {
+            count: false,
+            line_number: true,
+            colorize: false,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("hide-and-seek|divinity");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::Paragraph(None),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "14: I’m like someone searching at random, not knowing what object he’s
+15: looking for nor where it was hidden. We play hide-and-seek with no
+16: one. There’s a transcendent trick in all of this, a fluid divinity we can
+17: only hear.\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn paragraph_context_with_block_start() {
+        let flags = Flags {
+            count: false,
+            line_number: false,
+            colorize: false,
+            ignore_case: false,
+            invert_match: false,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("vanity");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::Paragraph(Some("^Yes")),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "Yes, I reread these pages that represent worthless hours, brief
+illusions or moments of calm, large hopes channelled into the
+landscape, sorrows like closed rooms, certain voices, a huge weariness,
+the unwritten gospel.
+
+We all have our vanity, and that vanity is our way of forgetting that
+there are other people with a soul like our own. My vanity consists of\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn near_matches_in_either_order() {
+        let flags = Flags {
+            line_number: true,
+            ..Flags::default()
+        };
+        let (reader, _, mut writer) = test_inputs("");
+        let regex = compile_patterns(&["soul|trick", "farewell|hide"], &flags).unwrap();
+        let stats = choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::Near("1"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "5: In these times when an abyss opens up in my soul, the tiniest detail
+6: distresses me like a letter of farewell. I feel as if I’m always on the
+####
+15: looking for nor where it was hidden. We play hide-and-seek with no
+16: one. There’s a transcendent trick in all of this, a fluid divinity we can\n"
+        );
+        // The soul on line 25 has no match of the other pattern nearby
+        assert_eq!(stats.matched_lines, 4);
+
+        let mut writer = Vec::new();
+        let regex = compile_patterns(&["one", "three"], &flags).unwrap();
+        let within = usize::MAX.to_string();
+        choose_process(
+            &b"one\ntwo\nthree\n"[..],
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::Near(&within),
+            "####",
+            "numbers.txt",
+        )
+        .unwrap();
+        assert_eq!(writer, b"1: one\n2: two\n3: three\n".to_vec());
+    }
+
+    #[test]
+    fn multiline_matches_with_line_number_and_color() {
+        let flags = Flags {
+            line_number: true,
+            colorize: true,
+            multiline: true,
+            ..Flags::default()
+        };
+        let (reader, _, mut writer) = test_inputs("");
+        let regex = Matcher::from(compile_regex(r"tiniest detail\ndistress", &flags).unwrap());
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "\u{1b}[32m5\u{1b}[39m: In these times when an abyss opens up in my soul, the \u{1b}[31mtiniest detail\u{1b}[39m
+\u{1b}[32m6\u{1b}[39m: \u{1b}[31mdistress\u{1b}[39mes me like a letter of farewell. I feel as if I’m always on the\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn multiline_only_matching_prints_whole_matches() {
+        let flags = Flags {
+            line_number: true,
+            only_matching: true,
+            multiline: true,
+            ..Flags::default()
+        };
+        let (reader, _, mut writer) = test_inputs("");
+        let regex =
+            Matcher::from(compile_regex(r"tiniest detail\ndistress|gospel", &flags).unwrap());
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "5: tiniest detail\ndistress\n22: gospel\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn number_of_multiline_matches() {
+        let flags = Flags {
+            count: true,
+            multiline: true,
+            ..Flags::default()
+        };
+        let (reader, _, _) = test_inputs("");
+        let regex = Matcher::from(compile_regex(r"^$\n^\w+|gospel", &flags).unwrap());
+        let stats = choose_process(
+            reader,
+            regex,
+            Vec::new(),
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(stats.matched_lines, 9);
+        assert_eq!(stats.matches, 5);
+    }
+
+    #[test]
+    fn null_data_records() {
+        let flags = Flags {
+            null_data: true,
+            ..Flags::default()
+        };
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex(r"\.txt$", &flags).unwrap());
+        choose_process(
+            &b"notes.txt\0my\nlist.txt\0image.png\0"[..],
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(writer, b"notes.txt\0my\nlist.txt\0".to_vec());
+    }
+
+    #[test]
+    fn crlf_lines_with_line_number() {
+        let flags = Flags {
+            line_number: true,
+            crlf: true,
+            ..Flags::default()
+        };
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex(r",2$", &flags).unwrap());
+        choose_process(
+            &b"id,count\r\nfoo,2\r\nbar,22\r\n"[..],
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(writer, b"2: foo,2\r\n".to_vec());
+    }
+
+    #[test]
+    fn crlf_lines_without_crlf_option() {
+        let flags = Flags::default();
+        let regex = Matcher::from(compile_regex(r",2$", &flags).unwrap());
+        let input = &b"id,count\r\nfoo,2\r\nbar,22\r\n"[..];
+        let mut writer = Vec::new();
+        choose_process(
+            input,
+            regex.clone(),
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "w.csv",
+        )
+        .unwrap();
+        assert_eq!(writer, b"foo,2\n".to_vec());
+
+        let flags = Flags {
+            multiline: true,
+            ..Flags::default()
+        };
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex(r"^foo", &flags).unwrap());
+        choose_process(
+            input,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "w.csv",
+        )
+        .unwrap();
+        assert_eq!(writer, b"foo,2\n".to_vec());
+    }
+
+    #[test]
+    fn crlf_lines_in_multiline_mode() {
+        let flags = Flags {
+            multiline: true,
+            byte_offset: true,
+            ..Flags::default()
+        };
+        let input = &b"id,count\r\nfoo,2\r\nbar,22\r\nbaz,2"[..];
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex(r"foo,2\nbar|,2$", &flags).unwrap());
+        choose_process(
+            input,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "w.csv",
+        )
+        .unwrap();
+        // Byte offsets are still those of the input, `\r`s included
+        assert_eq!(writer, b"10: foo,2\n17: bar,22\n25: baz,2\n".to_vec());
+    }
+
+    #[test]
+    fn files_with_matches_with_null() {
+        let flags = Flags {
+            files_with_matches: true,
+            null: true,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("vanity");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "my data/pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(writer, b"my data/pessoa.txt\0".to_vec());
+    }
+
+    #[test]
+    fn print_matches_with_file_name_and_null() {
+        let flags = Flags {
+            line_number: true,
+            null: true,
+            with_filename: true,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("distress");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "pessoa.txt\u{0}6: distresses me like a letter of farewell. I feel as if I’m always on the\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn print_matches_with_column_and_byte_offset() {
+        let flags = Flags {
+            line_number: true,
+            column: true,
+            byte_offset: true,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("hide-and-seek");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "15:46:749: looking for nor where it was hidden. We play hide-and-seek with no\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn after_context_with_column() {
+        let flags = Flags {
+            column: true,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("hide-and-seek");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::After("1"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "46: looking for nor where it was hidden. We play hide-and-seek with no
+one. There’s a transcendent trick in all of this, a fluid divinity we can\n"
+                .as_bytes()
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn hyperlinks_on_file_name_and_line_number() {
+        let flags = Flags {
+            line_number: true,
+            colorize: true,
+            with_filename: true,
+            hyperlink_format: Some(
+                "editor://open?file={path}&line={line}&column={column}".to_string(),
+            ),
+            color_scheme: ColorScheme {
+                file_name: Default::default(),
+                line_number: Default::default(),
+                ..ColorScheme::default()
+            },
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs("hide-and-seek");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "my data/pessoa.txt",
+        )
+        .unwrap();
+        let path = encode_path(
+            &std::path::absolute("my data/pessoa.txt")
+                .unwrap()
+                .to_string_lossy(),
+        );
+        assert!(path.ends_with("/my%20data/pessoa.txt"));
+        let url = format!("editor://open?file={}&line=15&column=46", path);
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            format!(
+                "\u{1b}]8;;{url}\u{1b}\\my data/pessoa.txt\u{1b}]8;;\u{1b}\\:\
+                 \u{1b}]8;;{url}\u{1b}\\15\u{1b}]8;;\u{1b}\\: \
+                 looking for nor where it was hidden. We play \u{1b}[31mhide-and-seek\u{1b}[39m with no\n",
+                url = url
+            )
+        );
+    }
+
+    #[test]
+    fn vimgrep_matches() {
+        let flags = Flags {
+            vimgrep: true,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs(r"\bour\b");
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "src/data/pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!(
+            writer,
+            "src/data/pessoa.txt:24:13:We all have our vanity, and that vanity is our way of forgetting that
+src/data/pessoa.txt:24:44:We all have our vanity, and that vanity is our way of forgetting that
+src/data/pessoa.txt:25:41:there are other people with a soul like our own. My vanity consists of\n"
                 .as_bytes()
                 .to_vec()
         );
     }
 
     #[test]
-    fn print_matches_without_line_number() {
+    fn json_matches_with_context() {
+        let flags = Flags {
+            json: true,
+            ..Flags::default()
+        };
+        let (reader, regex, mut writer) = test_inputs(r"\bour\b");
+        let stats = choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::Before("1"),
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
+        assert_eq!((stats.matched_lines, stats.matches), (2, 3));
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r#"{"type":"context","data":{"path":"pessoa.txt","line":"","line_number":23,"absolute_offset":1124,"submatches":[]}}
+{"type":"match","data":{"path":"pessoa.txt","line":"We all have our vanity, and that vanity is our way of forgetting that","line_number":24,"absolute_offset":1125,"submatches":[{"match":"our","start":12,"end":15},{"match":"our","start":43,"end":46}]}}
+{"type":"match","data":{"path":"pessoa.txt","line":"there are other people with a soul like our own. My vanity consists of","line_number":25,"absolute_offset":1195,"submatches":[{"match":"our","start":40,"end":43}]}}
+"#
+        );
+    }
+
+    #[test]
+    fn json_multiline_matches() {
+        let flags = Flags {
+            json: true,
+            multiline: true,
+            ..Flags::default()
+        };
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex(r"foo\nbar", &flags).unwrap());
+        let stats = choose_process(
+            &b"foo\nbar\nbaz\n"[..],
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::After("1"),
+            "####",
+            "m.txt",
+        )
+        .unwrap();
+        assert_eq!((stats.matched_lines, stats.matches), (2, 1));
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            r#"{"type":"match","data":{"path":"m.txt","line":"foo","line_number":1,"absolute_offset":0,"submatches":[{"match":"foo","start":0,"end":3}]}}
+{"type":"match","data":{"path":"m.txt","line":"bar","line_number":2,"absolute_offset":4,"submatches":[]}}
+{"type":"context","data":{"path":"m.txt","line":"baz","line_number":3,"absolute_offset":8,"submatches":[]}}
+"#
+        );
+    }
+
+    #[test]
+    fn stats_of_several_files() {
         let flags = Flags {
-            count: false,
-            line_number: false,
-            colorize: false,
-            ignore_case: false,
-            invert_match: false,
+            stats: true,
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("distress");
-        choose_process(
-            reader,
+        let (reader, regex, mut writer) = test_inputs(r"\bour\b");
+        let mut reader = CountingReader::new(reader);
+        let stats = choose_process(
+            &mut reader,
             regex,
-            &mut writer,
+            Vec::new(),
             &flags,
-            ContextKind::None,
+            ContextKind::Before("1"),
             "####",
+            "pessoa.txt",
         )
         .unwrap();
+        let stats = Stats {
+            bytes_searched: reader.bytes_read,
+            ..stats
+        };
         assert_eq!(
-            writer,
-            "distresses me like a letter of farewell. I feel as if I’m always on the\n"
-                .as_bytes()
-                .to_vec()
+            stats,
+            Stats {
+                matched_lines: 2,
+                matches: 3,
+                bytes_searched: 1266,
+            }
+        );
+        let file_stats = vec![
+            ("pessoa.txt".to_string(), stats),
+            ("empty.txt".to_string(), Stats::default()),
+        ];
+        print_stats(
+            &file_stats,
+            &stats,
+            1,
+            Duration::from_millis(1500),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "
+pessoa.txt: 2 matched lines, 3 matches, 1266 bytes searched
+empty.txt: 0 matched lines, 0 matches, 0 bytes searched
+
+2 matched lines
+3 matches
+1 files contained matches
+2 files searched
+1266 bytes searched
+1.500000 seconds spent searching
+"
         );
     }
 
     #[test]
-    fn after_context_without_line_number() {
+    fn stats_with_files_with_matches() {
         let flags = Flags {
-            count: false,
-            line_number: false,
-            colorize: false,
-            ignore_case: false,
-            invert_match: false,
+            files_with_matches: true,
+            stats: true,
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("distress");
-        choose_process(
-            reader,
+        let (reader, regex, mut writer) = test_inputs(r"\bour\b");
+        let mut reader = CountingReader::new(reader);
+        let stats = choose_process(
+            &mut reader,
             regex,
             &mut writer,
             &flags,
-            ContextKind::After("3"),
+            ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
-        assert_eq!(
-            writer,
-            "distresses me like a letter of farewell. I feel as if I’m always on the
-verge of waking up. I’m oppressed by the very self that encases me,
-asphyxiated by conclusions, and I’d gladly scream if my voice could
-reach somewhere. But there’s this heavy slumber that moves from one\n"
-                .as_bytes()
-                .to_vec()
-        );
+        // The file name is printed once, while every line is still read and counted
+        assert_eq!(writer, b"pessoa.txt\n".to_vec());
+        assert_eq!((stats.matched_lines, stats.matches), (2, 3));
+        assert_eq!(reader.bytes_read, 1266);
     }
 
     #[test]
-    fn after_context_with_line_number() {
+    fn replace_with_capture_groups() {
         let flags = Flags {
-            count: false,
             line_number: true,
-            colorize: false,
-            ignore_case: false,
-            invert_match: false,
+            replace: Some("${noun} ($1)".to_string()),
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("distress");
+        let (reader, _, mut writer) = test_inputs("");
+        let regex =
+            Matcher::from(compile_regex(r"(\w+) (?P<noun>slumber|gospel)", &flags).unwrap());
         choose_process(
             reader,
             regex,
             &mut writer,
             &flags,
-            ContextKind::After("3"),
+            ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
         assert_eq!(
             writer,
-            "\u{1b}[32m6\u{1b}[39m: distresses me like a letter of farewell. I feel as if I’m always on the
-\u{1b}[32m7\u{1b}[39m: verge of waking up. I’m oppressed by the very self that encases me,
-\u{1b}[32m8\u{1b}[39m: asphyxiated by conclusions, and I’d gladly scream if my voice could
-\u{1b}[32m9\u{1b}[39m: reach somewhere. But there’s this heavy slumber that moves from one\n"
+            "9: reach somewhere. But there’s this slumber (heavy) that moves from one
+22: the gospel (unwritten).\n"
                 .as_bytes()
                 .to_vec()
         );
     }
 
     #[test]
-    fn after_context_with_ten_char_words_and_color() {
+    fn replace_with_color() {
         let flags = Flags {
-            count: false,
-            line_number: false,
             colorize: true,
-            ignore_case: false,
-            invert_match: false,
+            replace: Some("[$0]".to_string()),
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs(r"\b\w{10}\b");
+        let (reader, regex, mut writer) = test_inputs(r"\bour\b");
         choose_process(
             reader,
             regex,
             &mut writer,
             &flags,
-            ContextKind::After("2"),
+            ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
         assert_eq!(
             writer,
-            "confused landscape, along with \u{1b}[31meverything\u{1b}[39m else.
-
-In these times when an abyss opens up in my soul, the tiniest detail
-\u{1b}[34m####\u{1b}[39m
-\u{1b}[31mdistresses\u{1b}[39m me like a letter of farewell. I feel as if I’m always on the
-verge of waking up. I’m oppressed by the very self that encases me,
-asphyxiated by conclusions, and I’d gladly scream if my voice could
-\u{1b}[34m####\u{1b}[39m
-group of my \u{1b}[31msensations\u{1b}[39m to another, like drifting clouds that make the
-half-shaded grass of sprawling fields turn various colours of sun and
-green.
-\u{1b}[34m####\u{1b}[39m
-illusions or moments of calm, large hopes \u{1b}[31mchannelled\u{1b}[39m into the
-landscape, sorrows like closed rooms, certain voices, a huge weariness,
-the unwritten gospel.
-\u{1b}[34m####\u{1b}[39m
-We all have our vanity, and that vanity is our way of \u{1b}[31mforgetting\u{1b}[39m that
-there are other people with a soul like our own. My vanity consists of\n"
+            "We all have \u{1b}[31m[our]\u{1b}[39m vanity, and that vanity is \u{1b}[31m[our]\u{1b}[39m way of forgetting that
+there are other people with a soul like \u{1b}[31m[our]\u{1b}[39m own. My vanity consists of\n"
                 .as_bytes()
                 .to_vec()
         );
     }
 
     #[test]
-    fn before_context_without_line_number() {
+    fn colors_per_pattern_and_capture_group() {
         let flags = Flags {
-            count: false,
-            line_number: false,
             colorize: true,
-            ignore_case: false,
-            invert_match: false,
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("distress");
+        let (reader, _, mut writer) = test_inputs("");
+        let regex =
+            compile_patterns(&["hide-and-(seek)", r"\bfluid (div(in)ity)"], &flags).unwrap();
         choose_process(
             reader,
             regex,
             &mut writer,
             &flags,
-            ContextKind::Before("3"),
+            ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
         assert_eq!(
-            writer,
-            "confused landscape, along with everything else.
+            String::from_utf8(writer).unwrap(),
+            "looking for nor where it was hidden. We play \u{1b}[31mhide-and-\u{1b}[39m\u{1b}[33mseek\u{1b}[39m with no
+one. There’s a transcendent trick in all of this, a \u{1b}[36mfluid \u{1b}[39m\u{1b}[32mdiv\u{1b}[39m\u{1b}[35min\u{1b}[39m\u{1b}[32mity\u{1b}[39m we can\n"
+        );
 
-In these times when an abyss opens up in my soul, the tiniest detail
-\u{1b}[31mdistress\u{1b}[39mes me like a letter of farewell. I feel as if I’m always on the\n"
-                .as_bytes()
-                .to_vec()
+        // A pattern following one with a capture group doesn't take the color of the group
+        let regex = compile_patterns(&["(a)b", "cd"], &flags).unwrap();
+        assert_eq!(
+            colorize_matches(&regex, "ab cd", &flags),
+            "\u{1b}[33ma\u{1b}[39m\u{1b}[31mb\u{1b}[39m \u{1b}[36mcd\u{1b}[39m"
         );
     }
 
     #[test]
-    fn before_context_with_line_number() {
+    fn boolean_query_with_color() {
         let flags = Flags {
-            count: false,
-            line_number: true,
             colorize: true,
-            ignore_case: false,
-            invert_match: false,
+            line_number: true,
+            query: Some(
+                Query::parse("soul AND (like OR tiniest) NOT other", &Flags::default()).unwrap(),
+            ),
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("distress");
+        let (reader, _, mut writer) = test_inputs("");
+        let terms = flags.query.as_ref().unwrap().highlighted_terms();
+        let regex = compile_patterns(&terms, &flags).unwrap();
         choose_process(
             reader,
             regex,
             &mut writer,
             &flags,
-            ContextKind::Before("3"),
+            ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
         assert_eq!(
-            writer,
-            "\u{1b}[32m3\u{1b}[39m: confused landscape, along with everything else.
-\u{1b}[32m4\u{1b}[39m: 
-\u{1b}[32m5\u{1b}[39m: In these times when an abyss opens up in my soul, the tiniest detail
-\u{1b}[32m6\u{1b}[39m: \u{1b}[31mdistress\u{1b}[39mes me like a letter of farewell. I feel as if I’m always on the\n"
-                .as_bytes()
-                .to_vec()
+            String::from_utf8(writer).unwrap(),
+            "\u{1b}[32m5\u{1b}[39m: In these times when an abyss opens up in my \u{1b}[31msoul\u{1b}[39m, the \u{1b}[36mtiniest\u{1b}[39m detail\n"
         );
     }
 
     #[test]
-    fn context_with_line_number() {
+    fn normalized_matches_with_only_matching_and_color() {
         let flags = Flags {
-            count: false,
-            line_number: true,
             colorize: true,
-            ignore_case: false,
-            invert_match: false,
+            only_matching: true,
+            line_number: true,
+            normalize: Some(Normalization::Nfkc),
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("like");
+        let (reader, _, mut writer) = test_inputs("");
+        let regex = compile_patterns(&["I'm", "he's"], &flags).unwrap();
         choose_process(
             reader,
             regex,
             &mut writer,
             &flags,
-            ContextKind::AfterAndBefore("2"),
+            ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
         assert_eq!(
-            writer,
-            "\u{1b}[32m4\u{1b}[39m: 
-\u{1b}[32m5\u{1b}[39m: In these times when an abyss opens up in my soul, the tiniest detail
-\u{1b}[32m6\u{1b}[39m: distresses me \u{1b}[31mlike\u{1b}[39m a letter of farewell. I feel as if I’m always on the
-\u{1b}[32m7\u{1b}[39m: verge of waking up. I’m oppressed by the very self that encases me,
-\u{1b}[32m8\u{1b}[39m: asphyxiated by conclusions, and I’d gladly scream if my voice could
-\u{1b}[34m####\u{1b}[39m
-\u{1b}[32m8\u{1b}[39m: asphyxiated by conclusions, and I’d gladly scream if my voice could
-\u{1b}[32m9\u{1b}[39m: reach somewhere. But there’s this heavy slumber that moves from one
-\u{1b}[32m10\u{1b}[39m: group of my sensations to another, \u{1b}[31mlike\u{1b}[39m drifting clouds that make the
-\u{1b}[32m11\u{1b}[39m: half-shaded grass of sprawling fields turn various colours of sun and
-\u{1b}[32m12\u{1b}[39m: green.
-\u{1b}[34m####\u{1b}[39m
-\u{1b}[32m12\u{1b}[39m: green.
-\u{1b}[32m13\u{1b}[39m: 
-\u{1b}[32m14\u{1b}[39m: I’m \u{1b}[31mlike\u{1b}[39m someone searching at random, not knowing what object he’s
-\u{1b}[32m15\u{1b}[39m: looking for nor where it was hidden. We play hide-and-seek with no
-\u{1b}[32m16\u{1b}[39m: one. There’s a transcendent trick in all of this, a fluid divinity we can
-\u{1b}[34m####\u{1b}[39m
-\u{1b}[32m19\u{1b}[39m: Yes, I reread these pages that represent worthless hours, brief
-\u{1b}[32m20\u{1b}[39m: illusions or moments of calm, large hopes channelled into the
-\u{1b}[32m21\u{1b}[39m: landscape, sorrows \u{1b}[31mlike\u{1b}[39m closed rooms, certain voices, a huge weariness,
-\u{1b}[32m22\u{1b}[39m: the unwritten gospel.
-\u{1b}[32m23\u{1b}[39m: 
-\u{1b}[34m####\u{1b}[39m
-\u{1b}[32m23\u{1b}[39m: 
-\u{1b}[32m24\u{1b}[39m: We all have our vanity, and that vanity is our way of forgetting that
-\u{1b}[32m25\u{1b}[39m: there are other people with a soul \u{1b}[31mlike\u{1b}[39m our own. My vanity consists of\n"
-                .as_bytes()
-                .to_vec()
+            String::from_utf8(writer).unwrap(),
+            "\u{1b}[32m2\u{1b}[39m: \u{1b}[31mI’m\u{1b}[39m
+\u{1b}[32m6\u{1b}[39m: \u{1b}[31mI’m\u{1b}[39m
+\u{1b}[32m7\u{1b}[39m: \u{1b}[31mI’m\u{1b}[39m
+\u{1b}[32m14\u{1b}[39m: \u{1b}[31mI’m\u{1b}[39m
+\u{1b}[32m14\u{1b}[39m: \u{1b}[33mhe’s\u{1b}[39m\n"
         );
     }
 
     #[test]
-    fn context_without_line_number() {
+    fn unreadable_files_are_file_errors() {
+        let flags = Flags::default();
+        let regex = Matcher::from(compile_regex("soul", &flags).unwrap());
+        let err = search_path(
+            Path::new("src/data/missing.txt"),
+            &regex,
+            &flags,
+            ContextKind::None,
+            "---",
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            CliError::File {
+                operation: "open",
+                ..
+            }
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("src/data/missing.txt: failed to open: "));
+    }
+
+    #[test]
+    fn colored_matches_keep_the_rest_of_the_line() {
         let flags = Flags {
-            count: false,
-            line_number: false,
             colorize: true,
-            ignore_case: false,
-            invert_match: false,
+            ..Flags::default()
         };
-        let (reader, regex, mut writer) = test_inputs("like");
+        let regex = Matcher::from(compile_regex(r"\$\d+|\b", &flags).unwrap());
+        assert_eq!(
+            colorize_matches(&regex, "from $10 to $25, or $1", &flags),
+            "from \u{1b}[31m$10\u{1b}[39m to \u{1b}[31m$25\u{1b}[39m, or \u{1b}[31m$1\u{1b}[39m"
+        );
+
+        // Apart from the color codes, the output is the same as the input
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex(r"\b\w{10}\b|’|(o)(u)?", &flags).unwrap());
+        let input = std::fs::read_to_string("src/data/pessoa.txt").unwrap();
         choose_process(
-            reader,
-            regex,
+            input.as_bytes(),
+            regex.clone(),
             &mut writer,
             &flags,
-            ContextKind::AfterAndBefore("2"),
+            ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
-        assert_eq!(
-            writer,
-            "
-In these times when an abyss opens up in my soul, the tiniest detail
-distresses me \u{1b}[31mlike\u{1b}[39m a letter of farewell. I feel as if I’m always on the
-verge of waking up. I’m oppressed by the very self that encases me,
-asphyxiated by conclusions, and I’d gladly scream if my voice could
-\u{1b}[34m####\u{1b}[39m
-asphyxiated by conclusions, and I’d gladly scream if my voice could
-reach somewhere. But there’s this heavy slumber that moves from one
-group of my sensations to another, \u{1b}[31mlike\u{1b}[39m drifting clouds that make the
-half-shaded grass of sprawling fields turn various colours of sun and
-green.
-\u{1b}[34m####\u{1b}[39m
-green.
-
-I’m \u{1b}[31mlike\u{1b}[39m someone searching at random, not knowing what object he’s
-looking for nor where it was hidden. We play hide-and-seek with no
-one. There’s a transcendent trick in all of this, a fluid divinity we can
-\u{1b}[34m####\u{1b}[39m
-Yes, I reread these pages that represent worthless hours, brief
-illusions or moments of calm, large hopes channelled into the
-landscape, sorrows \u{1b}[31mlike\u{1b}[39m closed rooms, certain voices, a huge weariness,
-the unwritten gospel.
-
-\u{1b}[34m####\u{1b}[39m
-
-We all have our vanity, and that vanity is our way of forgetting that
-there are other people with a soul \u{1b}[31mlike\u{1b}[39m our own. My vanity consists of\n"
-                .as_bytes()
-                .to_vec()
-        );
+        let output = String::from_utf8(writer).unwrap();
+        let without_colors = Regex::new("\u{1b}\\[[0-9;]*m")
+            .unwrap()
+            .replace_all(&output, "");
+        let matched_lines: Vec<&str> = input.lines().filter(|line| regex.is_match(line)).collect();
+        assert_eq!(without_colors.lines().collect::<Vec<&str>>(), matched_lines);
     }
 
     #[test]
@@ -778,9 +2411,19 @@ there are other people with a soul \u{1b}[31mlike\u{1b}[39m our own. My vanity c
             colorize: false,
             ignore_case: false,
             invert_match: true,
+            ..Flags::default()
         };
         let (reader, regex, mut writer) = test_inputs("like");
-        print_invert_matches(reader, regex, &flags, &mut writer).unwrap();
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
         assert_eq!(
             writer,
             "Like someone on a hill who tries to make out the people in the
@@ -816,36 +2459,77 @@ We all have our vanity, and that vanity is our way of forgetting that\n"
             colorize: false,
             ignore_case: false,
             invert_match: true,
+            ..Flags::default()
         };
         let (reader, regex, mut writer) = test_inputs("like");
-        print_invert_matches(reader, regex, &flags, &mut writer).unwrap();
+        choose_process(
+            reader,
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::None,
+            "####",
+            "pessoa.txt",
+        )
+        .unwrap();
         assert_eq!(
             writer,
-            "\u{1b}[32m1\u{1b}[39m: Like someone on a hill who tries to make out the people in the
-\u{1b}[32m2\u{1b}[39m: valley, I look down at myself from on high, and I’m a hazy and
-\u{1b}[32m3\u{1b}[39m: confused landscape, along with everything else.
-\u{1b}[32m4\u{1b}[39m: 
-\u{1b}[32m5\u{1b}[39m: In these times when an abyss opens up in my soul, the tiniest detail
-\u{1b}[32m7\u{1b}[39m: verge of waking up. I’m oppressed by the very self that encases me,
-\u{1b}[32m8\u{1b}[39m: asphyxiated by conclusions, and I’d gladly scream if my voice could
-\u{1b}[32m9\u{1b}[39m: reach somewhere. But there’s this heavy slumber that moves from one
-\u{1b}[32m11\u{1b}[39m: half-shaded grass of sprawling fields turn various colours of sun and
-\u{1b}[32m12\u{1b}[39m: green.
-\u{1b}[32m13\u{1b}[39m: 
-\u{1b}[32m15\u{1b}[39m: looking for nor where it was hidden. We play hide-and-seek with no
-\u{1b}[32m16\u{1b}[39m: one. There’s a transcendent trick in all of this, a fluid divinity we can
-\u{1b}[32m17\u{1b}[39m: only hear.
-\u{1b}[32m18\u{1b}[39m: 
-\u{1b}[32m19\u{1b}[39m: Yes, I reread these pages that represent worthless hours, brief
-\u{1b}[32m20\u{1b}[39m: illusions or moments of calm, large hopes channelled into the
-\u{1b}[32m22\u{1b}[39m: the unwritten gospel.
-\u{1b}[32m23\u{1b}[39m: 
-\u{1b}[32m24\u{1b}[39m: We all have our vanity, and that vanity is our way of forgetting that\n"
+            "1: Like someone on a hill who tries to make out the people in the
+2: valley, I look down at myself from on high, and I’m a hazy and
+3: confused landscape, along with everything else.
+4: 
+5: In these times when an abyss opens up in my soul, the tiniest detail
+7: verge of waking up. I’m oppressed by the very self that encases me,
+8: asphyxiated by conclusions, and I’d gladly scream if my voice could
+9: reach somewhere. But there’s this heavy slumber that moves from one
+11: half-shaded grass of sprawling fields turn various colours of sun and
+12: green.
+13: 
+15: looking for nor where it was hidden. We play hide-and-seek with no
+16: one. There’s a transcendent trick in all of this, a fluid divinity we can
+17: only hear.
+18: 
+19: Yes, I reread these pages that represent worthless hours, brief
+20: illusions or moments of calm, large hopes channelled into the
+22: the unwritten gospel.
+23: 
+24: We all have our vanity, and that vanity is our way of forgetting that\n"
                 .as_bytes()
                 .to_vec()
         );
     }
 
+    #[test]
+    fn invert_matches_with_context() {
+        let flags = Flags {
+            line_number: true,
+            invert_match: true,
+            ..Flags::default()
+        };
+        let mut writer = Vec::new();
+        let regex = Matcher::from(compile_regex("o", &flags).unwrap());
+        let stats = choose_process(
+            &b"one\ntwo\nthree\nfour\nfive\n"[..],
+            regex,
+            &mut writer,
+            &flags,
+            ContextKind::AfterAndBefore("1"),
+            "####",
+            "numbers.txt",
+        )
+        .unwrap();
+        assert_eq!((stats.matched_lines, stats.matches), (2, 0));
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "2: two
+3: three
+4: four
+####
+4: four
+5: five\n"
+        );
+    }
+
     #[test]
     fn multiple_matches_in_same_line_with_color() {
         let flags = Flags {
@@ -854,6 +2538,7 @@ We all have our vanity, and that vanity is our way of forgetting that\n"
             colorize: true,
             ignore_case: false,
             invert_match: false,
+            ..Flags::default()
         };
         let (reader, regex, mut writer) = test_inputs(r"\bour\b");
         choose_process(
@@ -863,6 +2548,7 @@ We all have our vanity, and that vanity is our way of forgetting that\n"
             &flags,
             ContextKind::None,
             "####",
+            "pessoa.txt",
         )
         .unwrap();
         assert_eq!(
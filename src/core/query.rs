@@ -0,0 +1,253 @@
+use regex::{RegexSet, RegexSetBuilder};
+
+use crate::core::flag::Flags;
+use crate::core::normalize::fold_pattern;
+
+/// A boolean expression over the terms of a query.
+#[derive(Debug)]
+enum Expr {
+    /// Matches lines matching the term at this index of `Query::terms`
+    Term(usize),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluates the expression, where `matched[i]` tells whether the term at index `i` matches.
+    fn eval(&self, matched: &[bool]) -> bool {
+        match self {
+            Expr::Term(i) => matched[*i],
+            Expr::Not(expr) => !expr.eval(matched),
+            Expr::And(left, right) => left.eval(matched) && right.eval(matched),
+            Expr::Or(left, right) => left.eval(matched) || right.eval(matched),
+        }
+    }
+}
+
+/// A token of a query.
+#[derive(Debug, PartialEq)]
+enum Token {
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Term(String),
+}
+
+/// Splits `query` into tokens. Terms are separated by whitespace and parentheses,
+/// unless they're quoted with `"`, in which case `\"` stands for a quote.
+fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '"' => {
+                chars.next();
+                let mut term = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if chars.peek() == Some(&'"') => {
+                            term.push(chars.next().unwrap())
+                        }
+                        Some(c) => term.push(c),
+                        None => return Err(format!("missing closing quote after \"{}", term)),
+                    }
+                }
+                tokens.push(Token::Term(term));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Term(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser for queries, where `OR` binds looser than `AND`,
+/// which binds looser than `NOT`. Terms next to each other are joined by `AND`.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// The terms found so far, and whether each of them is negated
+    terms: Vec<(String, bool)>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn or(&mut self, negated: bool) -> Result<Expr, String> {
+        let mut expr = self.and(negated)?;
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and(negated)?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self, negated: bool) -> Result<Expr, String> {
+        let mut expr = self.unary(negated)?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.position += 1,
+                Some(Token::Not) | Some(Token::Open) | Some(Token::Term(_)) => (),
+                _ => return Ok(expr),
+            }
+            expr = Expr::And(Box::new(expr), Box::new(self.unary(negated)?));
+        }
+    }
+
+    fn unary(&mut self, negated: bool) -> Result<Expr, String> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        match token {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary(!negated)?))),
+            Some(Token::Open) => {
+                let expr = self.or(negated)?;
+                match self.peek() {
+                    Some(Token::Close) => {
+                        self.position += 1;
+                        Ok(expr)
+                    }
+                    _ => Err("missing closing parenthesis".to_string()),
+                }
+            }
+            Some(Token::Term(term)) => {
+                self.terms.push((term.clone(), negated));
+                Ok(Expr::Term(self.terms.len() - 1))
+            }
+            Some(Token::Close) => Err("unexpected closing parenthesis".to_string()),
+            Some(Token::And) | Some(Token::Or) => {
+                Err("expected a term, NOT or an opening parenthesis before AND or OR".to_string())
+            }
+            None => Err("unexpected end of query: expected a term".to_string()),
+        }
+    }
+}
+
+/// A query combining terms with `AND`, `OR` and `NOT`, evaluated against each
+/// line, like `error AND (db OR cache) AND NOT retry`. Each term is a regular expression.
+#[derive(Debug)]
+pub(crate) struct Query {
+    expr: Expr,
+    /// The terms of the query, and whether each of them is negated
+    terms: Vec<(String, bool)>,
+    set: RegexSet,
+}
+
+impl Query {
+    /// Parses `query` and compiles its terms. With `--normalize` or `--ignore-accents`,
+    /// the literal characters of each term are folded like the input is, as described by
+    /// `fold_pattern`, once the query is split into terms so that its syntax is kept.
+    pub(crate) fn parse(query: &str, flags: &Flags) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(query)?,
+            position: 0,
+            terms: Vec::new(),
+        };
+        let expr = parser.or(false)?;
+        if parser.position < parser.tokens.len() {
+            return Err("unexpected closing parenthesis".to_string());
+        }
+        for (term, _) in parser.terms.iter_mut() {
+            *term = fold_pattern(term, flags).text.into_owned();
+        }
+        let set = RegexSetBuilder::new(parser.terms.iter().map(|(term, _)| term))
+            .case_insensitive(flags.ignore_case)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Query {
+            expr,
+            terms: parser.terms,
+            set,
+        })
+    }
+
+    /// Returns whether `line` satisfies the query.
+    pub(crate) fn is_match(&self, line: &str) -> bool {
+        let matches = self.set.matches(line);
+        let matched: Vec<bool> = (0..self.terms.len()).map(|i| matches.matched(i)).collect();
+        self.expr.eval(&matched)
+    }
+
+    /// Returns the terms that are highlighted in matching lines: those that aren't negated.
+    pub(crate) fn highlighted_terms(&self) -> Vec<&str> {
+        self.terms
+            .iter()
+            .filter(|(_, negated)| !negated)
+            .map(|(term, _)| term.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::normalize::Normalization;
+
+    #[test]
+    fn boolean_queries() {
+        let query = Query::parse(
+            r#"error AND (db OR cache) NOT "retry \"now\"""#,
+            &Flags::default(),
+        )
+        .unwrap();
+        assert!(query.is_match("error: db timed out"));
+        assert!(query.is_match("cache error"));
+        assert!(!query.is_match("error: disk full"));
+        assert!(!query.is_match("db error, retry \"now\""));
+        assert_eq!(query.highlighted_terms(), vec!["error", "db", "cache"]);
+
+        let ignore_case = Flags {
+            ignore_case: true,
+            ..Flags::default()
+        };
+        let query = Query::parse("NOT (a OR NOT b)", &ignore_case).unwrap();
+        assert!(query.is_match("B"));
+        assert!(!query.is_match("ab"));
+        assert_eq!(query.highlighted_terms(), vec!["b"]);
+
+        assert!(Query::parse("(error AND db", &Flags::default()).is_err());
+        assert!(Query::parse("error OR", &Flags::default()).is_err());
+        assert!(Query::parse("AND db", &Flags::default()).is_err());
+        assert!(Query::parse("error)", &Flags::default()).is_err());
+        assert!(Query::parse("erro[r", &Flags::default()).is_err());
+    }
+
+    #[test]
+    fn terms_are_folded_after_the_query_is_split() {
+        let flags = Flags {
+            normalize: Some(Normalization::Nfkc),
+            ..Flags::default()
+        };
+        // A full-width parenthesis is a literal one in a term, rather than a query parenthesis
+        let query = Query::parse("a （ AND ﬁle…", &flags).unwrap();
+        assert!(query.is_match("a (x file..."));
+        assert!(!query.is_match("a (x files"));
+        assert_eq!(query.highlighted_terms(), vec!["a", r"\(", r"file\.\.\."]);
+    }
+}
@@ -0,0 +1,339 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::core::error::CliError;
+use crate::core::flag::Flags;
+use crate::core::matcher::Matcher;
+use crate::core::process::line_matches;
+
+/// Number of unchanged lines shown around each change in a diff.
+const DIFF_CONTEXT: usize = 3;
+
+/// Applies the `--replace` template to every match in `content`. With `--query`, only
+/// the lines satisfying it are rewritten, as described by `line_matches`.
+/// Returns the lines of `content` and the lines of the result, both with their terminators.
+///
+/// In multiline mode, the whole of `content` is replaced at once, so the result
+/// can have a different number of lines.
+fn replace_lines<'c>(content: &'c str, re: &Matcher, flags: &Flags) -> (Vec<&'c str>, Vec<String>) {
+    let template = flags.replace.as_deref().unwrap_or_default();
+    let terminator = flags.record_terminator() as char;
+    let old_lines: Vec<&str> = content.split_inclusive(terminator).collect();
+
+    if flags.multiline {
+        let replaced = re.replace_all(content, |mat| mat.expand(template));
+        let new_lines = replaced
+            .split_inclusive(terminator)
+            .map(String::from)
+            .collect();
+        return (old_lines, new_lines);
+    }
+
+    let new_lines = old_lines
+        .iter()
+        .map(|line| {
+            // Only the line itself is matched, not its terminator, which is kept as it is
+            let mut end = line.strip_suffix(terminator).unwrap_or(line).len();
+            if end < line.len() && terminator == '\n' && line[..end].ends_with('\r') {
+                end -= 1;
+            }
+            let (text, terminator) = line.split_at(end);
+            match line_matches(re, text, flags) {
+                true => format!(
+                    "{}{}",
+                    re.replace_all(text, |mat| mat.expand(template)),
+                    terminator
+                ),
+                false => line.to_string(),
+            }
+        })
+        .collect();
+    (old_lines, new_lines)
+}
+
+/// Rewrites the file at `path` with the replacements of `--replace` applied.
+/// With `--dry-run`, the file is left untouched and a unified diff of what would
+/// change is written to `writer` instead. Files without matches are skipped.
+pub(crate) fn rewrite_file(
+    path: &Path,
+    re: &Matcher,
+    flags: &Flags,
+    mut writer: impl Write,
+) -> Result<(), CliError> {
+    let file_name = path.display().to_string();
+    let content =
+        fs::read_to_string(path).map_err(|err| CliError::file(&file_name, "read", err))?;
+    let (old_lines, new_lines) = replace_lines(&content, re, flags);
+    if old_lines
+        .iter()
+        .copied()
+        .eq(new_lines.iter().map(String::as_str))
+    {
+        return Ok(());
+    }
+
+    if flags.dry_run {
+        write_unified_diff(&mut writer, &file_name, &old_lines, &new_lines)?;
+        writer.flush()?;
+    } else {
+        write_atomically(path, new_lines.concat().as_bytes())
+            .map_err(|err| CliError::file(&file_name, "rewrite", err))?;
+    }
+    Ok(())
+}
+
+/// Replaces the contents of the file at `path` with `contents`. They're written
+/// to a temporary file in the same directory first, which is then renamed over
+/// `path`, so that `path` never holds partially written contents.
+/// The permissions of `path` are kept.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let permissions = fs::metadata(path)?.permissions();
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.grab-{}.tmp", file_name, std::process::id()));
+
+    let result = (|| {
+        let mut temp_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        temp_file.write_all(contents)?;
+        temp_file.sync_all()?;
+        fs::set_permissions(&temp_path, permissions)?;
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        // The original file is untouched, so only the leftover needs cleaning up
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// A run of changed lines: `old[old_start..old_end]` became `new[new_start..new_end]`.
+#[derive(Debug, PartialEq)]
+struct Change {
+    old_start: usize,
+    old_end: usize,
+    new_start: usize,
+    new_end: usize,
+}
+
+/// Finds the runs of lines that differ between `old` and `new`.
+///
+/// When both have as many lines, they're compared line by line, which is what
+/// line-by-line replacing produces. Otherwise, everything between their common
+/// leading and trailing lines makes up a single change.
+fn find_changes(old: &[&str], new: &[String]) -> Vec<Change> {
+    if old.len() == new.len() {
+        let mut changes: Vec<Change> = Vec::new();
+        for i in (0..old.len()).filter(|&i| old[i] != new[i]) {
+            match changes.last_mut() {
+                Some(change) if change.old_end == i => {
+                    change.old_end += 1;
+                    change.new_end += 1;
+                }
+                _ => changes.push(Change {
+                    old_start: i,
+                    old_end: i + 1,
+                    new_start: i,
+                    new_end: i + 1,
+                }),
+            }
+        }
+        return changes;
+    }
+
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(o, n)| **o == n.as_str())
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(o, n)| **o == n.as_str())
+        .count();
+    vec![Change {
+        old_start: prefix,
+        old_end: old.len() - suffix,
+        new_start: prefix,
+        new_end: new.len() - suffix,
+    }]
+}
+
+/// Writes a single line of a diff, prefixed with `marker`. A line missing its
+/// terminator is the last one of its file, which is pointed out like `diff` does.
+fn write_diff_line(writer: &mut impl Write, marker: char, line: &str) -> io::Result<()> {
+    write!(writer, "{}{}", marker, line)?;
+    if !line.ends_with('\n') {
+        write!(writer, "\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+/// Writes a unified diff between the lines `old` and `new` of the file `path`,
+/// with `DIFF_CONTEXT` unchanged lines around each change. Changes closer than
+/// that to each other are grouped into the same hunk.
+fn write_unified_diff(
+    writer: &mut impl Write,
+    path: &str,
+    old: &[&str],
+    new: &[String],
+) -> io::Result<()> {
+    let changes = find_changes(old, new);
+    writeln!(writer, "--- a/{}", path)?;
+    writeln!(writer, "+++ b/{}", path)?;
+
+    let mut hunk_start = 0;
+    while hunk_start < changes.len() {
+        // Changes whose context overlaps belong to the same hunk
+        let mut hunk_end = hunk_start + 1;
+        while hunk_end < changes.len()
+            && changes[hunk_end].old_start - changes[hunk_end - 1].old_end <= 2 * DIFF_CONTEXT
+        {
+            hunk_end += 1;
+        }
+        let (first, last) = (&changes[hunk_start], &changes[hunk_end - 1]);
+        let old_start = first.old_start.saturating_sub(DIFF_CONTEXT);
+        let old_end = (last.old_end + DIFF_CONTEXT).min(old.len());
+        // Lines outside of changes are the same on both sides
+        let new_start = old_start + first.new_start - first.old_start;
+        let new_end = old_end + last.new_end - last.old_end;
+
+        // An empty range starts at the line before it, as in `diff -u`
+        let range = |start: usize, end: usize| match end - start {
+            0 => format!("{},0", start),
+            len => format!("{},{}", start + 1, len),
+        };
+        writeln!(
+            writer,
+            "@@ -{} +{} @@",
+            range(old_start, old_end),
+            range(new_start, new_end)
+        )?;
+
+        let mut i = old_start;
+        for change in &changes[hunk_start..hunk_end] {
+            for line in &old[i..change.old_start] {
+                write_diff_line(writer, ' ', line)?;
+            }
+            for line in &old[change.old_start..change.old_end] {
+                write_diff_line(writer, '-', line)?;
+            }
+            for line in &new[change.new_start..change.new_end] {
+                write_diff_line(writer, '+', line)?;
+            }
+            i = change.old_end;
+        }
+        for line in &old[i..old_end] {
+            write_diff_line(writer, ' ', line)?;
+        }
+        hunk_start = hunk_end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::query::Query;
+    use crate::core::utils::{compile_patterns, compile_regex};
+
+    #[test]
+    fn diff_of_replaced_lines() {
+        let flags = Flags {
+            replace: Some("[$0]".to_string()),
+            ..Flags::default()
+        };
+        let content = fs::read_to_string("src/data/pessoa.txt").unwrap();
+        let regex = Matcher::from(compile_regex(r"\bsoul\b", &flags).unwrap());
+        let (old_lines, new_lines) = replace_lines(&content, &regex, &flags);
+        let mut writer = Vec::new();
+        write_unified_diff(&mut writer, "pessoa.txt", &old_lines, &new_lines).unwrap();
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "--- a/pessoa.txt
++++ b/pessoa.txt
+@@ -2,7 +2,7 @@
+ valley, I look down at myself from on high, and I’m a hazy and
+ confused landscape, along with everything else.
+\x20
+-In these times when an abyss opens up in my soul, the tiniest detail
++In these times when an abyss opens up in my [soul], the tiniest detail
+ distresses me like a letter of farewell. I feel as if I’m always on the
+ verge of waking up. I’m oppressed by the very self that encases me,
+ asphyxiated by conclusions, and I’d gladly scream if my voice could
+@@ -22,4 +22,4 @@
+ the unwritten gospel.
+\x20
+ We all have our vanity, and that vanity is our way of forgetting that
+-there are other people with a soul like our own. My vanity consists of
++there are other people with a [soul] like our own. My vanity consists of
+"
+        );
+    }
+
+    #[test]
+    fn multiline_changes_without_trailing_newline() {
+        let flags = Flags {
+            multiline: true,
+            replace: Some("one".to_string()),
+            ..Flags::default()
+        };
+        let regex = Matcher::from(compile_regex(r"two\nthree", &flags).unwrap());
+        let (old_lines, new_lines) = replace_lines("one\ntwo\nthree", &regex, &flags);
+        let mut writer = Vec::new();
+        write_unified_diff(&mut writer, "numbers.txt", &old_lines, &new_lines).unwrap();
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            "--- a/numbers.txt
++++ b/numbers.txt
+@@ -1,3 +1,2 @@
+ one
+-two
+-three
+\\ No newline at end of file
++one
+\\ No newline at end of file
+"
+        );
+    }
+
+    #[test]
+    fn rewrite_file_in_place() {
+        let flags = Flags {
+            crlf: true,
+            replace: Some("$2,$1".to_string()),
+            ..Flags::default()
+        };
+        let path = std::env::temp_dir().join(format!("grab-rewrite-{}.csv", std::process::id()));
+        fs::write(&path, "id,name\r\n1,foo\r\n").unwrap();
+        let regex = Matcher::from(compile_regex(r"^(\w+),(\w+)$", &flags).unwrap());
+        rewrite_file(&path, &regex, &flags, Vec::new()).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content, "name,id\r\nfoo,1\r\n");
+
+        // Without `--crlf`, the `\r` isn't matched either, and is kept as it is
+        let flags = Flags {
+            replace: Some("$2,$1".to_string()),
+            ..Flags::default()
+        };
+        let (_, new_lines) = replace_lines("id,name\r\n1,foo", &regex, &flags);
+        assert_eq!(new_lines, vec!["name,id\r\n", "foo,1"]);
+    }
+
+    #[test]
+    fn only_lines_satisfying_the_query_are_replaced() {
+        let flags = Flags {
+            replace: Some("X".to_string()),
+            query: Some(Query::parse("error AND NOT retry", &Flags::default()).unwrap()),
+            ..Flags::default()
+        };
+        let terms = flags.query.as_ref().unwrap().highlighted_terms();
+        let regex = compile_patterns(&terms, &flags).unwrap();
+        let (_, new_lines) = replace_lines("error db\nerror db retry\n", &regex, &flags);
+        assert_eq!(new_lines, vec!["X db\n", "error db retry\n"]);
+    }
+}
@@ -1,6 +1,12 @@
-use crate::core::error::CliError;
-use owo_colors::OwoColorize;
+use crate::core::error::{CliError, PatternError};
+use crate::core::flag::Flags;
+use crate::core::fuzzy::FuzzyMatcher;
+use crate::core::matcher::Matcher;
+use crate::core::normalize::{fold, fold_pattern, Folded};
+use crate::core::posix::{translate, PosixSyntax};
 use regex::RegexBuilder;
+use regex_syntax::ParserBuilder;
+use std::io::{self, BufRead, Read};
 
 /// Creates a new `BufWriter` object to write to the standard output stream.
 #[macro_export]
@@ -13,25 +19,6 @@ macro_rules! getwriter {
     }};
 }
 
-/// Contains colors to apply to patterns like group separators and matches
-pub(crate) enum Colors {
-    Red,
-    Green,
-    Blue,
-}
-
-impl Colors {
-    /// Colorizes a `pattern`. Based on the variant of `Colors` provided, it
-    /// calls the appropriate function of the `Colorize` trait.
-    pub(crate) fn colorize_pattern(color: Self, pattern: &str) -> String {
-        match color {
-            Self::Red => pattern.red().to_string(),
-            Self::Green => pattern.green().to_string(),
-            Self::Blue => pattern.blue().to_string(),
-        }
-    }
-}
-
 /// Represents the type of context lines.
 #[derive(Clone, Copy)]
 pub(crate) enum ContextKind<'ctx> {
@@ -41,20 +28,268 @@ pub(crate) enum ContextKind<'ctx> {
     Before(&'ctx str),
     /// Both trailing and leading
     AfterAndBefore(&'ctx str),
+    /// The whole block around a match. Blocks are delimited by blank lines,
+    /// or start at each line matching the given regex
+    Paragraph(Option<&'ctx str>),
+    /// The lines between a match of one of two patterns and a match of the other
+    /// at most the given number of lines away
+    Near(&'ctx str),
     /// No context
     None,
 }
 
-/// Tries to parse the context number.
-pub(crate) fn parse_context_number(ctx: &str) -> Result<usize, CliError> {
-    ctx.parse::<usize>().map_err(|err| err.into())
+/// Tries to parse the context number given to `flag`.
+pub(crate) fn parse_context_number(ctx: &str, flag: &'static str) -> Result<usize, CliError> {
+    ctx.parse::<usize>().map_err(|error| CliError::Flag {
+        flag,
+        value: ctx.to_string(),
+        error,
+    })
+}
+
+/// Compiles the regular expression given by `p`. A syntax error in it is reported
+/// as a `PatternError`, pointing at where it is.
+///
+/// In multiline mode, `^` and `$` also match at the start and end of every line,
+/// since the whole input is searched at once.
+pub(crate) fn compile_regex(p: &str, flags: &Flags) -> Result<regex::Regex, CliError> {
+    let re = RegexBuilder::new(p)
+        .case_insensitive(flags.ignore_case)
+        .multi_line(flags.multiline)
+        .build();
+    match re {
+        Ok(re) => Ok(re),
+        Err(err) => {
+            check_syntax(p, p, |span| span, flags).map_err(CliError::Pattern)?;
+            Err(err.into())
+        }
+    }
+}
+
+/// Checks the syntax of the pattern `p`, rewritten from the pattern `original` that the
+/// user gave, so that a syntax error in it is reported as a `PatternError`, pointing at
+/// where it is in `original`. `original_span` maps byte ranges of `p` back to `original`.
+fn check_syntax(
+    p: &str,
+    original: &str,
+    original_span: impl Fn((usize, usize)) -> (usize, usize),
+    flags: &Flags,
+) -> Result<(), PatternError> {
+    ParserBuilder::new()
+        .case_insensitive(flags.ignore_case)
+        .multi_line(flags.multiline)
+        .build()
+        .parse(p)
+        .map(|_| ())
+        .map_err(|err| PatternError::mapped(original, &err, original_span))
+}
+
+/// Rewrites the pattern `p` as the user gave it into the syntax of the `regex` crate:
+/// escaped with `-F`, translated from the POSIX syntax with `-G` or `-E`, or else as it is.
+fn rewrite<'p>(p: &'p str, flags: &Flags) -> Result<Folded<'p>, PatternError> {
+    match (flags.fixed_strings, flags.syntax) {
+        (true, _) => {
+            let mut escaped = String::with_capacity(p.len());
+            let mut starts: Vec<(usize, usize)> = Vec::with_capacity(p.len());
+            for (offset, c) in p.char_indices() {
+                starts.push((escaped.len(), offset));
+                escaped.push_str(&regex::escape(c.encode_utf8(&mut [0; 4])));
+            }
+            Ok(Folded::rewritten(p, escaped, starts))
+        }
+        (false, Some(syntax)) => translate(p, syntax),
+        (false, None) => Ok(Folded::unchanged(p)),
+    }
+}
+
+/// Prefix of the names of the capture groups that `compile_patterns` wraps each pattern in.
+const PATTERN_GROUP_PREFIX: &str = "grab_pattern_";
+
+/// Compiles several patterns into a single regular expression matching any of them.
+///
+/// A single pattern is compiled as it is. Otherwise, each pattern is wrapped in a
+/// named capture group, so that `pattern_index` can tell which of them a match comes from.
+/// Without any pattern, as for a query made only of negated terms, nothing matches.
+///
+/// With `-F`, each pattern is taken literally. With `-G` or `-E`, each pattern is first
+/// translated from the POSIX syntax, as described by `translate`. With `--normalize` or
+/// `--ignore-accents`, the literal characters of each pattern are folded like the input
+/// is, as described by `fold_pattern`. With `--fuzzy`, the patterns are taken literally
+/// and searched for by a `FuzzyMatcher` instead, with as many edits as allowed.
+pub(crate) fn compile_patterns(patterns: &[&str], flags: &Flags) -> Result<Matcher, CliError> {
+    let rewritten: Vec<Folded> = patterns
+        .iter()
+        .map(|p| rewrite(p, flags))
+        .collect::<Result<_, _>>()
+        .map_err(CliError::Pattern)?;
+    if let Some(edits) = flags.fuzzy {
+        // The patterns are taken literally, so they're folded like the input is
+        let patterns: Vec<String> = rewritten
+            .iter()
+            .map(|p| fold(&p.text, flags).text.into_owned())
+            .collect();
+        return Ok(Matcher::Fuzzy(FuzzyMatcher::new(
+            &patterns,
+            edits,
+            flags.ignore_case,
+        )?));
+    }
+    let folded: Vec<Folded> = rewritten
+        .iter()
+        .map(|p| fold_pattern(&p.text, flags))
+        .collect();
+    let patterns: Vec<String> = folded.iter().map(|p| p.text.to_string()).collect();
+    // Syntax errors are reported about the pattern they're in as the user gave it, rather
+    // than about its rewriting or the alternation
+    for (rewritten, folded) in rewritten.iter().zip(&folded) {
+        let original_span = |span| rewritten.original_span(folded.original_span(span));
+        check_syntax(&folded.text, rewritten.original, original_span, flags).map_err(|error| {
+            // The advice is about the syntax of the `regex` crate, where basic regular
+            // expressions escape the other way around
+            CliError::Pattern(match flags.syntax {
+                Some(PosixSyntax::Basic) => error.without_hint(),
+                _ => error,
+            })
+        })?;
+    }
+    match patterns.as_slice() {
+        [] => return compile_regex(r"\b\B", flags).map(Matcher::Regex),
+        [pattern] => return compile_regex(pattern, flags).map(Matcher::Regex),
+        _ => (),
+    }
+    let alternation: Vec<String> = patterns
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!("(?P<{}{}>{})", PATTERN_GROUP_PREFIX, i, p))
+        .collect();
+    compile_regex(&alternation.join("|"), flags).map(Matcher::Regex)
+}
+
+/// Returns the index of the pattern given to `compile_patterns` that the capture group
+/// named `name` wraps, or `None` for the capture groups written in the patterns themselves.
+pub(crate) fn pattern_index(name: Option<&str>) -> Option<usize> {
+    name?.strip_prefix(PATTERN_GROUP_PREFIX)?.parse().ok()
 }
 
-/// Compiles the regular expression given by `p`.
-pub(crate) fn compile_regex(p: &str, is_case_insensitive: bool) -> Result<regex::Regex, CliError> {
-    let re = match is_case_insensitive {
-        true => RegexBuilder::new(p).case_insensitive(true).build()?,
-        false => RegexBuilder::new(p).build()?,
-    };
-    Ok(re)
+/// An iterator over the records of a `BufRead`, split on the terminator chosen
+/// by `Flags` rather than always on newlines.
+///
+/// Like `BufRead::lines`, the records don't include their terminator, and
+/// a `\r` right before a newline terminator is stripped as well.
+pub(crate) struct Lines<B> {
+    reader: B,
+    terminator: u8,
+}
+
+impl<B: BufRead> Lines<B> {
+    /// Reads the next record, alongside the number of bytes it took up
+    /// in `reader`, terminator included.
+    fn next_record(&mut self) -> Option<io::Result<(usize, String)>> {
+        let mut buf = Vec::new();
+        match self.reader.read_until(self.terminator, &mut buf) {
+            Ok(0) => None,
+            Ok(len) => {
+                if buf.last() == Some(&self.terminator) {
+                    buf.pop();
+                    if self.terminator == b'\n' && buf.last() == Some(&b'\r') {
+                        buf.pop();
+                    }
+                }
+                Some(
+                    String::from_utf8(buf)
+                        .map(|line| (len, line))
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+                )
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl<B: BufRead> Iterator for Lines<B> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record()
+            .map(|record| record.map(|(_, line)| line))
+    }
+}
+
+/// Returns an iterator over the records of `reader`, as described by `Lines`.
+pub(crate) fn lines<B: BufRead>(reader: B, flags: &Flags) -> Lines<B> {
+    Lines {
+        reader,
+        terminator: flags.record_terminator(),
+    }
+}
+
+/// Like `lines`, but pairs each record with the 0-based byte offset
+/// it starts at within `reader`.
+pub(crate) fn lines_with_byte_offsets<B: BufRead>(
+    reader: B,
+    flags: &Flags,
+) -> impl Iterator<Item = io::Result<(usize, String)>> {
+    let mut lines = lines(reader, flags);
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let record = lines.next_record()?;
+        Some(record.map(|(len, line)| {
+            let start = offset;
+            offset += len;
+            (start, line)
+        }))
+    })
+}
+
+/// A `BufRead` that keeps track of the number of bytes read from it.
+pub(crate) struct CountingReader<R> {
+    reader: R,
+    pub(crate) bytes_read: usize,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        CountingReader {
+            reader,
+            bytes_read: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.bytes_read += len;
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.bytes_read += amt;
+        self.reader.consume(amt)
+    }
+}
+
+/// Quotes `s` as a JSON string, escaping the characters that need it.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
 }
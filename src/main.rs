@@ -10,29 +10,54 @@ use crate::core::utils::ContextKind;
 fn main() {
     let args = Cli::new().parse();
 
-    let pattern = args.value_of("pattern").unwrap();
-    let input = Path::new(args.value_of("input").unwrap_or("STDIN"));
-    let group_separator = args.value_of("group_separator").unwrap_or("---");
-
     let flags = Flags::set_flags(&args);
 
+    // With `--regexp`, `--query` or `--near`, the first positional argument is a file rather than the pattern
+    let first_input = || {
+        args.value_of("pattern")
+            .map(Path::new)
+            .into_iter()
+            .collect()
+    };
+    let given_patterns = args.values_of("regexp").or_else(|| args.values_of("near"));
+    let (patterns, mut inputs): (Vec<&str>, Vec<&Path>) = match (given_patterns, &flags.query) {
+        (Some(patterns), _) => (patterns.collect(), first_input()),
+        (None, Some(query)) => (query.highlighted_terms(), first_input()),
+        (None, None) => (vec![args.value_of("pattern").unwrap()], Vec::new()),
+    };
+    inputs.extend(args.values_of("input").into_iter().flatten().map(Path::new));
+    if inputs.is_empty() && flags.in_place {
+        fatal!("error: --in-place needs at least one file to rewrite");
+    }
+    // Matching lines are printed one by one, while a match can span several of them
+    if flags.multiline && flags.replace.is_some() && !flags.in_place {
+        fatal!(
+            "error: --replace can't be printed with --multiline: use it with --in-place instead"
+        );
+    }
+    if inputs.is_empty() {
+        inputs.push(Path::new("STDIN"));
+    }
+    let group_separator = args.value_of("group_separator").unwrap_or("---");
+
     let context_kind = if args.is_present("after_context") {
         ContextKind::After(args.value_of("after_context").unwrap())
     } else if args.is_present("before_context") {
         ContextKind::Before(args.value_of("before_context").unwrap())
     } else if args.is_present("context") {
         ContextKind::AfterAndBefore(args.value_of("context").unwrap())
+    } else if args.is_present("paragraph") || args.is_present("block_start") {
+        ContextKind::Paragraph(args.value_of("block_start"))
+    } else if args.is_present("near") {
+        ContextKind::Near(args.value_of("within").unwrap())
     } else {
         ContextKind::None
     };
 
-    if let Err(e) = prepare_and_choose(
-        (pattern, flags.ignore_case),
-        input,
-        &flags,
-        context_kind,
-        group_separator,
-    ) {
-        fatal!("error: {e}");
+    match prepare_and_choose(&patterns, &inputs, &flags, context_kind, group_separator) {
+        Ok(true) => (),
+        // Some files couldn't be searched
+        Ok(false) => std::process::exit(2),
+        Err(e) => fatal!("error: {e}"),
     }
 }